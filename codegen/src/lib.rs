@@ -1,4 +1,6 @@
 pub mod linker;
+pub mod partitions;
 
-
-
+/// Re-exported so a build.rs can lay out partitions with `moonboot::partitions!` and pass the
+/// resulting constants to the [linker] script generators without depending on moonboot itself
+pub use moonboot;
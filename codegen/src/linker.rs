@@ -1,21 +1,27 @@
 use moonboot::{
-    hardware::{Config, LinkerConfig},
-    state::{StateCrcType, STATE_SERIALIZED_MAX_SIZE},
-    Address,
+    hardware::{Bank, Config, LinkerConfig},
+    image::IMAGE_HEADER_SIZE,
+    state::STATE_RESERVED_SIZE,
 };
+
 fn generate_linker_script(
-    flash_origin: Address,
-    flash_length: Address,
-    ram_origin: Address,
-    ram_length: Address,
-    with_ram_state: bool,
+    config: Config,
+    linker_config: LinkerConfig,
+    flash: Bank,
+    with_header: bool,
 ) -> String {
-    let ram_length = ram_length as usize;
-    if with_ram_state {
-        let crc_length = core::mem::size_of::<StateCrcType>(); // 4 byte crc
-        let data_len_length = core::mem::size_of::<u32>();
-        let state_length = STATE_SERIALIZED_MAX_SIZE;
-        let ram_length = ram_length as usize - state_length - crc_length - data_len_length;
+    let flash_origin = linker_config.flash_origin + flash.location;
+    let flash_length = flash.size;
+    let ram_origin = linker_config.ram_origin + config.ram_bank.location;
+    let ram_length = config.ram_bank.size as usize;
+
+    let memory = if linker_config.has_ram_state {
+        // Always reserve the same amount of RAM, so the state stays at the same address even if
+        // bootloader and application disagree on its size
+        let state_length = STATE_RESERVED_SIZE;
+        let ram_length = ram_length
+            .checked_sub(state_length)
+            .expect("RAM is too small to reserve the shared state, validate the configuration");
         let state_origin = ram_origin as usize + ram_length;
         format!(
             "
@@ -23,22 +29,21 @@ fn generate_linker_script(
     MEMORY {{
         FLASH : ORIGIN = 0x{flash_origin:08x}, LENGTH = {flash_length}
         RAM : ORIGIN = 0x{ram_origin:08x}, LENGTH = {ram_length}
-        MOONBOOT_STATE: ORIGIN = 0x{state_origin:08x}, LENGTH = {state_section_length}
+        MOONBOOT_STATE: ORIGIN = 0x{state_origin:08x}, LENGTH = {state_length}
     }}
 
-    _moonboot_state_crc_start = ORIGIN(MOONBOOT_STATE);
-    _moonboot_state_len_start = ORIGIN(MOONBOOT_STATE) + {crc_length};
-    _moonboot_state_data_start = ORIGIN(MOONBOOT_STATE) + {crc_length} + {data_len_length};
-    PROVIDE(_moonboots_pre_jump = __moonboots_default_pre_jump);
+    SECTIONS {{
+        .moonboot_state (NOLOAD) : {{
+            KEEP(*(.moonboot_state));
+        }} > MOONBOOT_STATE
+    }} INSERT AFTER .bss;
 ",
             flash_origin = flash_origin,
             flash_length = flash_length,
             ram_origin = ram_origin,
             ram_length = ram_length,
             state_origin = state_origin,
-            state_section_length = state_length + crc_length + data_len_length,
-            data_len_length = data_len_length,
-            crc_length = crc_length,
+            state_length = state_length,
         )
     } else {
         format!(
@@ -47,36 +52,152 @@ fn generate_linker_script(
         FLASH : ORIGIN = 0x{flash_origin:08x}, LENGTH = {flash_length}
         RAM : ORIGIN = 0x{ram_origin:08x}, LENGTH = {ram_length}
     }}
-    
-    PROVIDE(_moonboots_pre_jump = __moonboots_default_pre_jump);
 ",
             flash_origin = flash_origin,
             flash_length = flash_length,
             ram_origin = ram_origin,
             ram_length = ram_length,
         )
-    }
+    };
+
+    let bank_symbols = |name: &str, bank: Bank| {
+        format!(
+            "
+    _moonboot_{name}_start = 0x{start:08x};
+    _moonboot_{name}_size = {size};",
+            name = name,
+            start = linker_config.flash_origin + bank.location,
+            size = bank.size,
+        )
+    };
+    let symbols = [
+        bank_symbols("bootloader", config.bootloader_bank),
+        bank_symbols("boot_bank", config.boot_bank),
+        bank_symbols("update_bank", config.update_bank),
+    ]
+    .concat();
+
+    // Place the header at its fixed offset right after the vector table, the linker complains if
+    // the vector table is too large to fit in front of it. cortex-m-rt starts .text at _stext,
+    // which defaults to the end of the vector table, so move it behind the header.
+    let header = match config.image_header_offset {
+        Some(header_offset) if with_header => format!(
+            "
+    SECTIONS {{
+        .moonboot_header ORIGIN(FLASH) + 0x{header_offset:x} : {{
+            KEEP(*(.moonboot_header));
+            . = {header_size};
+        }} > FLASH
+    }} INSERT AFTER .vector_table;
+
+    _stext = ORIGIN(FLASH) + 0x{header_offset:x} + {header_size};
+",
+            header_offset = header_offset,
+            header_size = IMAGE_HEADER_SIZE,
+        ),
+        _ => String::new(),
+    };
+
+    format!(
+        "{memory}{symbols}
+{header}
+    PROVIDE(_moonboots_pre_jump = __moonboots_default_pre_jump);
+    PROVIDE(_moonboots_on_update_start = __moonboots_default_on_update_start);
+    PROVIDE(_moonboots_on_exchange_progress = __moonboots_default_on_exchange_progress);
+    PROVIDE(_moonboots_on_update_complete = __moonboots_default_on_update_complete);
+    PROVIDE(_moonboots_on_revert = __moonboots_default_on_revert);
+    PROVIDE(_moonboots_on_error = __moonboots_default_on_error);
+",
+        memory = memory,
+        symbols = symbols,
+        header = header,
+    )
 }
 
+/// Generate the memory.x linker script of the bootloader. Besides the memory layout, it exports the
+/// location and size of the bootloader, boot and update bank as `_moonboot_bootloader_start`,
+/// `_moonboot_bootloader_size`, `_moonboot_boot_bank_start`, `_moonboot_boot_bank_size`,
+/// `_moonboot_update_bank_start` and `_moonboot_update_bank_size`.
 pub fn generate_bootloader_script(config: Config, linker_config: LinkerConfig) -> String {
-    generate_linker_script(
-        linker_config.flash_origin + config.bootloader_bank.location,
-        config.bootloader_bank.size,
-        linker_config.ram_origin + config.ram_bank.location,
-        config.ram_bank.size,
-        linker_config.has_ram_state,
-    )
+    generate_linker_script(config, linker_config, config.bootloader_bank, false)
 }
 
+/// Generate the memory.x linker script of the application, exporting the same symbols as
+/// [generate_bootloader_script]. If `Config::image_header_offset` is set, the `.moonboot_header`
+/// input section is placed at that offset of the image, which requires the output section
+/// `.vector_table` of cortex-m-rt, and `.text` is moved behind it by setting `_stext`. Put the
+/// image header there with `#[link_section = ".moonboot_header"]`.
 pub fn generate_application_script(config: Config, linker_config: LinkerConfig) -> String {
     // find the bootable image
     let bootable_firmware = config.boot_bank;
 
-    generate_linker_script(
-        linker_config.flash_origin + bootable_firmware.location,
-        bootable_firmware.size,
-        linker_config.ram_origin + config.ram_bank.location,
-        config.ram_bank.size,
-        linker_config.has_ram_state,
-    )
+    generate_linker_script(config, linker_config, bootable_firmware, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use moonboot::hardware::MemoryUnit;
+
+    fn bank(location: u32, size: u32) -> Bank {
+        Bank {
+            location,
+            size,
+            memory_unit: MemoryUnit::Internal,
+        }
+    }
+
+    fn config(image_header_offset: Option<u32>) -> Config {
+        Config {
+            boot_bank: bank(0x10000, 0x20000),
+            update_bank: bank(0x30000, 0x20000),
+            bootloader_bank: bank(0, 0x10000),
+            ram_bank: bank(0, 0x20000),
+            image_header_offset,
+            additional_images: [None; 3],
+            history_bank: None,
+        }
+    }
+
+    const LINKER_CONFIG: LinkerConfig = LinkerConfig {
+        flash_origin: 0x0800_0000,
+        ram_origin: 0x2000_0000,
+        has_ram_state: true,
+    };
+
+    #[test]
+    fn text_starts_after_the_image_header() {
+        let script = generate_application_script(config(Some(0x400)), LINKER_CONFIG);
+
+        assert!(script.contains("FLASH : ORIGIN = 0x08010000, LENGTH = 131072"));
+        assert!(script.contains(".moonboot_header ORIGIN(FLASH) + 0x400"));
+        assert!(script.contains(&format!(
+            "_stext = ORIGIN(FLASH) + 0x400 + {};",
+            IMAGE_HEADER_SIZE
+        )));
+    }
+
+    #[test]
+    fn state_is_reserved_at_the_end_of_ram() {
+        let script = generate_bootloader_script(config(None), LINKER_CONFIG);
+
+        assert!(script.contains(&format!(
+            "RAM : ORIGIN = 0x20000000, LENGTH = {}",
+            0x20000 - STATE_RESERVED_SIZE
+        )));
+        assert!(script.contains(&format!(
+            "MOONBOOT_STATE: ORIGIN = 0x{:08x}, LENGTH = {}",
+            0x2002_0000 - STATE_RESERVED_SIZE,
+            STATE_RESERVED_SIZE
+        )));
+    }
+
+    #[test]
+    fn bootloader_has_no_image_header() {
+        let script = generate_bootloader_script(config(Some(0x400)), LINKER_CONFIG);
+
+        assert!(script.contains("FLASH : ORIGIN = 0x08000000, LENGTH = 65536"));
+        assert!(!script.contains(".moonboot_header"));
+        assert!(!script.contains("_stext"));
+    }
 }
@@ -0,0 +1,565 @@
+use std::{fmt, fs, io, path::Path};
+
+use moonboot::{
+    hardware::{Bank, Config, ImageBanks, LinkerConfig, MemoryUnit, MAX_IMAGES},
+    image::IMAGE_HEADER_SIZE,
+    state::STATE_RESERVED_SIZE,
+    Address,
+};
+use serde::Deserialize;
+
+use crate::linker;
+
+/// Declarative description of the partitioning of a device, usually parsed from a TOML file in the
+/// build.rs of both bootloader and application, so both always agree on the layout:
+///
+/// ```toml
+/// image_header_offset = 0x400 # optional
+///
+/// [flash]
+/// origin = 0x08000000
+/// size = 0x80000
+/// erase_size = 0x4000
+///
+/// [ram]
+/// origin = 0x20000000
+/// size = 0x20000
+/// state = true
+///
+/// [bootloader]
+/// location = 0x08000000
+/// size = 0x10000
+///
+/// [boot]
+/// location = 0x08010000
+/// size = 0x20000
+///
+/// [update]
+/// location = 0x08030000
+/// size = 0x20000
+///
+/// # optional, update history
+/// [history]
+/// location = 0x08070000
+/// size = 0x4000
+///
+/// # optional, up to three additional images
+/// [[images]]
+/// boot = { location = 0x08050000, size = 0x10000 }
+/// update = { location = 0x08060000, size = 0x10000 }
+/// ```
+///
+/// All locations are absolute addresses. In the generated [Config], they are relative to the
+/// origins of flash and RAM, which are part of the [LinkerConfig].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct PartitionTable {
+    /// Internal non-volatile memory of the device
+    pub flash: Flash,
+    /// Internal RAM of the device
+    pub ram: Ram,
+    /// Partition containing the bootloader
+    pub bootloader: Partition,
+    /// Partition the main firmware is booted from
+    pub boot: Partition,
+    /// Partition updates of the main firmware are written to
+    pub update: Partition,
+    /// Additional images updated together with the main firmware
+    #[serde(default)]
+    pub images: Vec<ImagePartitions>,
+    /// Offset of the image header from the start of the boot and update partitions
+    pub image_header_offset: Option<Address>,
+    /// Partition the update history is recorded in
+    pub history: Option<Partition>,
+}
+
+/// Description of the internal non-volatile memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Flash {
+    /// Start address of the memory
+    pub origin: Address,
+    /// Size of the memory in bytes
+    pub size: Address,
+    /// Size of the smallest erasable unit in bytes, all partitions have to be aligned to it
+    pub erase_size: Address,
+}
+
+/// Description of the internal RAM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Ram {
+    /// Start address of the memory
+    pub origin: Address,
+    /// Size of the memory in bytes
+    pub size: Address,
+    /// Whether to reserve memory at the end of the RAM for the shared state
+    #[serde(default)]
+    pub state: bool,
+}
+
+/// A single partition of the internal non-volatile memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Partition {
+    /// Start address of the partition
+    pub location: Address,
+    /// Size of the partition in bytes
+    pub size: Address,
+}
+
+/// Partitions of an additional image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ImagePartitions {
+    /// Partition the image is used from
+    pub boot: Partition,
+    /// Partition updates of the image are written to
+    pub update: Partition,
+}
+
+/// Errors found while reading or validating a [PartitionTable]
+#[derive(Debug)]
+pub enum PartitionError {
+    /// The partition table file could not be read
+    Io(io::Error),
+    /// The partition table is not valid TOML or misses entries
+    Parse(toml::de::Error),
+    /// There are more additional images than supported
+    TooManyImages(usize),
+    /// A partition has a size of zero
+    ZeroSize(String),
+    /// A partition does not start or end at a multiple of the erase size
+    Misaligned(String),
+    /// A partition lies outside of the memory it belongs to
+    OutOfBounds(String),
+    /// Two partitions share memory
+    Overlap(String, String),
+    /// Boot and update partition of an image differ in size and can therefore not be exchanged
+    SizeMismatch(String),
+    /// The image header does not fit into the boot partition
+    InvalidHeaderOffset(Address),
+    /// The RAM is too small to reserve the shared state at its end, see [STATE_RESERVED_SIZE]
+    RamTooSmall(Address),
+}
+
+impl fmt::Display for PartitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionError::Io(err) => write!(f, "could not read partition table: {}", err),
+            PartitionError::Parse(err) => write!(f, "could not parse partition table: {}", err),
+            PartitionError::TooManyImages(count) => write!(
+                f,
+                "{} additional images configured, at most {} are supported",
+                count,
+                MAX_IMAGES - 1
+            ),
+            PartitionError::ZeroSize(name) => write!(f, "partition {} has a size of zero", name),
+            PartitionError::Misaligned(name) => write!(
+                f,
+                "partition {} is not aligned to the flash erase size",
+                name
+            ),
+            PartitionError::OutOfBounds(name) => {
+                write!(f, "partition {} lies outside of its memory", name)
+            }
+            PartitionError::Overlap(a, b) => write!(f, "partitions {} and {} overlap", a, b),
+            PartitionError::SizeMismatch(name) => {
+                write!(f, "boot and update partition of {} differ in size", name)
+            }
+            PartitionError::InvalidHeaderOffset(offset) => write!(
+                f,
+                "image header at offset 0x{:x} does not fit into the boot partition",
+                offset
+            ),
+            PartitionError::RamTooSmall(size) => write!(
+                f,
+                "RAM of {} bytes is too small to reserve {} bytes for the shared state",
+                size, STATE_RESERVED_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PartitionError {}
+
+impl From<io::Error> for PartitionError {
+    fn from(err: io::Error) -> Self {
+        PartitionError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for PartitionError {
+    fn from(err: toml::de::Error) -> Self {
+        PartitionError::Parse(err)
+    }
+}
+
+impl Partition {
+    fn end(&self) -> u64 {
+        self.location as u64 + self.size as u64
+    }
+
+    // The partition as a bank relative to the given origin, only valid after validation
+    fn bank(&self, origin: Address) -> Bank {
+        Bank {
+            location: self.location - origin,
+            size: self.size,
+            memory_unit: MemoryUnit::Internal,
+        }
+    }
+}
+
+impl PartitionTable {
+    /// Parse and validate a partition table
+    pub fn from_toml(source: &str) -> Result<Self, PartitionError> {
+        let table: PartitionTable = toml::from_str(source)?;
+        table.validate()?;
+        Ok(table)
+    }
+
+    /// Read, parse and validate a partition table file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, PartitionError> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Check the partitions for overlaps, alignment to the erase size of the flash, equal sizes of
+    /// exchanged partitions and whether they fit into their memories
+    pub fn validate(&self) -> Result<(), PartitionError> {
+        if self.images.len() > MAX_IMAGES - 1 {
+            return Err(PartitionError::TooManyImages(self.images.len()));
+        }
+
+        let partitions = self.flash_partitions();
+        let flash = Partition {
+            location: self.flash.origin,
+            size: self.flash.size,
+        };
+
+        for (name, partition) in &partitions {
+            if partition.size == 0 {
+                return Err(PartitionError::ZeroSize(name.clone()));
+            }
+            if partition.location < flash.location || partition.end() > flash.end() {
+                return Err(PartitionError::OutOfBounds(name.clone()));
+            }
+            if self.flash.erase_size == 0
+                || !(partition.location - self.flash.origin).is_multiple_of(self.flash.erase_size)
+                || !partition.size.is_multiple_of(self.flash.erase_size)
+            {
+                return Err(PartitionError::Misaligned(name.clone()));
+            }
+        }
+
+        for (index, (name, partition)) in partitions.iter().enumerate() {
+            for (other_name, other) in &partitions[index + 1..] {
+                if (partition.location as u64) < other.end()
+                    && (other.location as u64) < partition.end()
+                {
+                    return Err(PartitionError::Overlap(name.clone(), other_name.clone()));
+                }
+            }
+        }
+
+        let pairs = core::iter::once(("main image".to_string(), self.boot, self.update)).chain(
+            self.images
+                .iter()
+                .enumerate()
+                .map(|(index, image)| (format!("image {}", index + 1), image.boot, image.update)),
+        );
+        for (name, boot, update) in pairs {
+            if boot.size != update.size {
+                return Err(PartitionError::SizeMismatch(name));
+            }
+            if let Some(offset) = self.image_header_offset {
+                if offset as u64 + IMAGE_HEADER_SIZE as u64 > boot.size as u64 {
+                    return Err(PartitionError::InvalidHeaderOffset(offset));
+                }
+            }
+        }
+
+        let ram = Partition {
+            location: self.ram.origin,
+            size: self.ram.size,
+        };
+        if ram.size == 0 {
+            return Err(PartitionError::ZeroSize("ram".to_string()));
+        }
+        if ram.end() > u32::MAX as u64 + 1 {
+            return Err(PartitionError::OutOfBounds("ram".to_string()));
+        }
+        if self.ram.state && (self.ram.size as usize) < STATE_RESERVED_SIZE {
+            return Err(PartitionError::RamTooSmall(self.ram.size));
+        }
+
+        Ok(())
+    }
+
+    // All partitions of the flash with their names
+    fn flash_partitions(&self) -> Vec<(String, Partition)> {
+        let mut partitions = vec![
+            ("bootloader".to_string(), self.bootloader),
+            ("boot".to_string(), self.boot),
+            ("update".to_string(), self.update),
+        ];
+        for (index, image) in self.images.iter().enumerate() {
+            partitions.push((format!("images[{}].boot", index), image.boot));
+            partitions.push((format!("images[{}].update", index), image.update));
+        }
+        if let Some(history) = self.history {
+            partitions.push(("history".to_string(), history));
+        }
+        partitions
+    }
+
+    /// The [Config] described by this partition table
+    pub fn config(&self) -> Config {
+        let origin = self.flash.origin;
+        let mut additional_images = [None; MAX_IMAGES - 1];
+        for (slot, image) in additional_images.iter_mut().zip(&self.images) {
+            *slot = Some(ImageBanks {
+                boot_bank: image.boot.bank(origin),
+                update_bank: image.update.bank(origin),
+            });
+        }
+
+        Config {
+            boot_bank: self.boot.bank(origin),
+            update_bank: self.update.bank(origin),
+            bootloader_bank: self.bootloader.bank(origin),
+            ram_bank: Bank {
+                location: 0,
+                size: self.ram.size,
+                memory_unit: MemoryUnit::Internal,
+            },
+            image_header_offset: self.image_header_offset,
+            additional_images,
+            history_bank: self.history.map(|history| history.bank(origin)),
+        }
+    }
+
+    /// The [LinkerConfig] described by this partition table
+    pub fn linker_config(&self) -> LinkerConfig {
+        LinkerConfig {
+            flash_origin: self.flash.origin,
+            ram_origin: self.ram.origin,
+            has_ram_state: self.ram.state,
+        }
+    }
+
+    /// Generate Rust source code defining the [Config] as `MOONBOOT_CONFIG`, the [LinkerConfig] as
+    /// `MOONBOOT_LINKER_CONFIG` and the flash erase size as `MOONBOOT_PAGE_SIZE`, to be
+    /// `include!`d by bootloader and application
+    pub fn generate_config(&self) -> String {
+        let config = self.config();
+        let linker_config = self.linker_config();
+
+        let additional_images = config
+            .additional_images
+            .iter()
+            .map(|image| match image {
+                Some(image) => format!(
+                    "Some(moonboot::hardware::ImageBanks {{ boot_bank: {}, update_bank: {} }})",
+                    bank_source(&image.boot_bank),
+                    bank_source(&image.update_bank)
+                ),
+                None => "None".to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let image_header_offset = match config.image_header_offset {
+            Some(offset) => format!("Some(0x{:x})", offset),
+            None => "None".to_string(),
+        };
+
+        let history_bank = match config.history_bank {
+            Some(bank) => format!("Some({})", bank_source(&bank)),
+            None => "None".to_string(),
+        };
+
+        format!(
+            "/// Partitioning of this device, generated by moonboot-codegen
+pub const MOONBOOT_CONFIG: moonboot::hardware::Config = moonboot::hardware::Config {{
+    boot_bank: {boot_bank},
+    update_bank: {update_bank},
+    bootloader_bank: {bootloader_bank},
+    ram_bank: {ram_bank},
+    image_header_offset: {image_header_offset},
+    additional_images: [{additional_images}],
+    history_bank: {history_bank},
+}};
+
+/// Origins of flash and RAM, generated by moonboot-codegen
+pub const MOONBOOT_LINKER_CONFIG: moonboot::hardware::LinkerConfig =
+    moonboot::hardware::LinkerConfig {{
+        flash_origin: 0x{flash_origin:08x},
+        ram_origin: 0x{ram_origin:08x},
+        has_ram_state: {has_ram_state},
+    }};
+
+/// Erase size of the internal flash, generated by moonboot-codegen
+pub const MOONBOOT_PAGE_SIZE: usize = {page_size};
+",
+            boot_bank = bank_source(&config.boot_bank),
+            update_bank = bank_source(&config.update_bank),
+            bootloader_bank = bank_source(&config.bootloader_bank),
+            ram_bank = bank_source(&config.ram_bank),
+            image_header_offset = image_header_offset,
+            additional_images = additional_images,
+            history_bank = history_bank,
+            flash_origin = linker_config.flash_origin,
+            ram_origin = linker_config.ram_origin,
+            has_ram_state = linker_config.has_ram_state,
+            page_size = self.flash.erase_size,
+        )
+    }
+
+    /// Generate the linker script for the bootloader
+    pub fn generate_bootloader_script(&self) -> String {
+        linker::generate_bootloader_script(self.config(), self.linker_config())
+    }
+
+    /// Generate the linker script for the application
+    pub fn generate_application_script(&self) -> String {
+        linker::generate_application_script(self.config(), self.linker_config())
+    }
+}
+
+fn bank_source(bank: &Bank) -> String {
+    format!(
+        "moonboot::hardware::Bank {{ location: 0x{:08x}, size: 0x{:x}, memory_unit: moonboot::hardware::MemoryUnit::Internal }}",
+        bank.location, bank.size
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = r#"
+        image_header_offset = 0x400
+
+        [flash]
+        origin = 0x08000000
+        size = 0x80000
+        erase_size = 0x4000
+
+        [ram]
+        origin = 0x20000000
+        size = 0x20000
+        state = true
+
+        [bootloader]
+        location = 0x08000000
+        size = 0x10000
+
+        [boot]
+        location = 0x08010000
+        size = 0x20000
+
+        [update]
+        location = 0x08030000
+        size = 0x20000
+
+        [history]
+        location = 0x08070000
+        size = 0x4000
+
+        [[images]]
+        boot = { location = 0x08050000, size = 0x10000 }
+        update = { location = 0x08060000, size = 0x10000 }
+    "#;
+
+    #[test]
+    fn config_is_relative_to_the_origins() {
+        let table = PartitionTable::from_toml(TABLE).unwrap();
+        let config = table.config();
+
+        assert_eq!(config.bootloader_bank.location, 0);
+        assert_eq!(config.boot_bank.location, 0x10000);
+        assert_eq!(config.update_bank.location, 0x30000);
+        assert_eq!(config.history_bank.unwrap().location, 0x70000);
+        assert_eq!(config.ram_bank.location, 0);
+        assert_eq!(table.linker_config().flash_origin, 0x08000000);
+        assert_eq!(table.linker_config().ram_origin, 0x20000000);
+        assert_eq!(config.validate(0x4000), Ok(()));
+        assert_eq!(config.validate_capacity(0x80000), Ok(()));
+    }
+
+    #[test]
+    fn overlapping_partitions_are_rejected() {
+        let table = TABLE.replace(
+            "[update]\n        location = 0x08030000",
+            "[update]\n        location = 0x08020000",
+        );
+        assert!(matches!(
+            PartitionTable::from_toml(&table),
+            Err(PartitionError::Overlap(a, b)) if a == "boot" && b == "update"
+        ));
+
+        let table = TABLE.replace(
+            "[history]\n        location = 0x08070000",
+            "[history]\n        location = 0x08064000",
+        );
+        assert!(matches!(
+            PartitionTable::from_toml(&table),
+            Err(PartitionError::Overlap(a, b)) if a == "images[0].update" && b == "history"
+        ));
+    }
+
+    #[test]
+    fn misaligned_partitions_are_rejected() {
+        let table = TABLE.replace(
+            "[history]\n        location = 0x08070000",
+            "[history]\n        location = 0x08071000",
+        );
+        assert!(matches!(
+            PartitionTable::from_toml(&table),
+            Err(PartitionError::Misaligned(name)) if name == "history"
+        ));
+
+        let table = TABLE.replace(
+            "[bootloader]\n        location = 0x08000000\n        size = 0x10000",
+            "[bootloader]\n        location = 0x08000000\n        size = 0x9000",
+        );
+        assert!(matches!(
+            PartitionTable::from_toml(&table),
+            Err(PartitionError::Misaligned(name)) if name == "bootloader"
+        ));
+    }
+
+    #[test]
+    fn partitions_outside_of_the_flash_are_rejected() {
+        let table = TABLE.replace(
+            "[history]\n        location = 0x08070000\n        size = 0x4000",
+            "[history]\n        location = 0x08070000\n        size = 0x14000",
+        );
+        assert!(matches!(
+            PartitionTable::from_toml(&table),
+            Err(PartitionError::OutOfBounds(name)) if name == "history"
+        ));
+    }
+
+    #[test]
+    fn ram_too_small_for_the_state_is_rejected() {
+        let table = TABLE.replace(
+            "size = 0x20000\n        state",
+            "size = 0x80\n        state",
+        );
+        assert!(matches!(
+            PartitionTable::from_toml(&table),
+            Err(PartitionError::RamTooSmall(0x80))
+        ));
+
+        let table = table.replace("state = true", "state = false");
+        assert!(PartitionTable::from_toml(&table).is_ok());
+    }
+
+    #[test]
+    fn exchanged_partitions_must_match_in_size() {
+        let table = TABLE.replace(
+            "update = { location = 0x08060000, size = 0x10000 }",
+            "update = { location = 0x08060000, size = 0x8000 }",
+        );
+        assert!(matches!(
+            PartitionTable::from_toml(&table),
+            Err(PartitionError::SizeMismatch(name)) if name == "image 1"
+        ));
+    }
+}
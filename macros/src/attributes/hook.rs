@@ -0,0 +1,111 @@
+use proc_macro::TokenStream;
+use proc_macro_error::{abort, abort_call_site};
+use quote::quote;
+use syn::{parse_macro_input, Attribute, FnArg, ItemFn, ReturnType, Type};
+
+/// Handler fn invoked by moonboot through an extern symbol
+pub(crate) struct Hook {
+    /// Name of the attribute, used in error messages
+    pub(crate) name: &'static str,
+    /// Symbol the handler is exported as
+    pub(crate) symbol: &'static str,
+    /// Name and type of every parameter of the handler
+    pub(crate) params: &'static [(&'static str, &'static str)],
+}
+
+pub(crate) fn expand(hook: &Hook, args: TokenStream, item: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        abort_call_site!("`#[moonboot::{}]` attribute takes no arguments", hook.name);
+    }
+
+    let fun = parse_macro_input!(item as ItemFn);
+
+    validate(hook, &fun);
+
+    codegen(hook, &fun)
+}
+
+fn validate(hook: &Hook, fun: &ItemFn) {
+    let is_default_return = matches!(&fun.sig.output, ReturnType::Default);
+    let has_receiver = fun
+        .sig
+        .inputs
+        .iter()
+        .any(|input| matches!(input, FnArg::Receiver(_)));
+
+    if fun.sig.constness.is_some()
+        || fun.sig.asyncness.is_some()
+        || fun.sig.unsafety.is_some()
+        || fun.sig.abi.is_some()
+        || !fun.sig.generics.params.is_empty()
+        || fun.sig.generics.where_clause.is_some()
+        || fun.sig.variadic.is_some()
+        || fun.sig.inputs.len() != hook.params.len()
+        || has_receiver
+        || !is_default_return
+    {
+        let params = hook
+            .params
+            .iter()
+            .map(|(name, ty)| format!("{}: {}", name, ty))
+            .collect::<Vec<_>>()
+            .join(", ");
+        abort!(
+            fun.sig.ident,
+            "function must have signature `fn({}) -> ()`",
+            params
+        );
+    }
+
+    check_for_attribute_conflicts(hook.name, &fun.attrs, &["export_name", "no_mangle"]);
+}
+
+/// Checks if any attribute in `attrs_to_check` is in `reject_list` and returns a compiler error if there's a match
+///
+/// The compiler error will indicate that the attribute conflicts with `attr_name`
+fn check_for_attribute_conflicts(
+    attr_name: &str,
+    attrs_to_check: &[Attribute],
+    reject_list: &[&str],
+) {
+    for attr in attrs_to_check {
+        if let Some(ident) = attr.path.get_ident() {
+            let ident = ident.to_string();
+
+            if reject_list.contains(&ident.as_str()) {
+                abort!(
+                    attr,
+                    "`#[{}]` attribute cannot be used together with `#[{}]`",
+                    attr_name,
+                    ident
+                )
+            }
+        }
+    }
+}
+
+fn codegen(hook: &Hook, fun: &ItemFn) -> TokenStream {
+    let attrs = &fun.attrs;
+    let block = &fun.block;
+    let ident = &fun.sig.ident;
+    let inputs = &fun.sig.inputs;
+    let symbol = hook.symbol;
+    let types = hook
+        .params
+        .iter()
+        .map(|(_, ty)| syn::parse_str::<Type>(ty).expect("invalid parameter type"));
+
+    // The symbol is called through an extern declaration, so make sure the parameter types match
+    // it exactly
+    quote!(
+        #(#attrs)*
+        #[export_name = #symbol]
+        #[inline(never)]
+        fn #ident(#inputs) -> () {
+            #block
+        }
+
+        const _: fn(#(#types),*) = #ident;
+    )
+    .into()
+}
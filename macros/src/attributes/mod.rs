@@ -1 +1 @@
-pub(crate) mod pre_jump_handler;
+pub(crate) mod hook;
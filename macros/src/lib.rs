@@ -2,10 +2,78 @@ use proc_macro::TokenStream;
 use proc_macro_error::proc_macro_error;
 
 mod attributes;
+mod partitions;
+
+use attributes::hook::{self, Hook};
 
 #[proc_macro_attribute]
 #[proc_macro_error]
 pub fn pre_jump_handler(args: TokenStream, item: TokenStream) -> TokenStream {
-    attributes::pre_jump_handler::expand(args, item)
+    let hook = Hook {
+        name: "pre_jump_handler",
+        symbol: "_moonboots_pre_jump",
+        params: &[],
+    };
+    hook::expand(&hook, args, item)
+}
+
+#[proc_macro_attribute]
+#[proc_macro_error]
+pub fn on_update_start(args: TokenStream, item: TokenStream) -> TokenStream {
+    let hook = Hook {
+        name: "on_update_start",
+        symbol: "_moonboots_on_update_start",
+        params: &[],
+    };
+    hook::expand(&hook, args, item)
+}
+
+#[proc_macro_attribute]
+#[proc_macro_error]
+pub fn on_exchange_progress(args: TokenStream, item: TokenStream) -> TokenStream {
+    let hook = Hook {
+        name: "on_exchange_progress",
+        symbol: "_moonboots_on_exchange_progress",
+        params: &[("page", "u32"), ("total", "u32")],
+    };
+    hook::expand(&hook, args, item)
 }
 
+#[proc_macro_attribute]
+#[proc_macro_error]
+pub fn on_update_complete(args: TokenStream, item: TokenStream) -> TokenStream {
+    let hook = Hook {
+        name: "on_update_complete",
+        symbol: "_moonboots_on_update_complete",
+        params: &[],
+    };
+    hook::expand(&hook, args, item)
+}
+
+#[proc_macro_attribute]
+#[proc_macro_error]
+pub fn on_revert(args: TokenStream, item: TokenStream) -> TokenStream {
+    let hook = Hook {
+        name: "on_revert",
+        symbol: "_moonboots_on_revert",
+        params: &[],
+    };
+    hook::expand(&hook, args, item)
+}
+
+#[proc_macro_attribute]
+#[proc_macro_error]
+pub fn on_error(args: TokenStream, item: TokenStream) -> TokenStream {
+    let hook = Hook {
+        name: "on_error",
+        symbol: "_moonboots_on_error",
+        params: &[("error", "moonboot::state::UpdateError")],
+    };
+    hook::expand(&hook, args, item)
+}
+
+#[proc_macro]
+#[proc_macro_error]
+pub fn partitions(input: TokenStream) -> TokenStream {
+    partitions::expand(input)
+}
@@ -0,0 +1,409 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use proc_macro_error::abort;
+use quote::quote;
+use syn::{
+    braced, bracketed,
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    punctuated::Punctuated,
+    token, Ident, LitBool, LitInt, Token,
+};
+
+pub(crate) fn expand(input: TokenStream) -> TokenStream {
+    let mut fields = parse_macro_input!(input as Fields);
+
+    let layout = Layout::from_fields(&mut fields);
+    fields.finish();
+
+    codegen(&layout)
+}
+
+// Value assigned to a key in the macro input
+enum Value {
+    Int(LitInt),
+    Bool(LitBool),
+    Struct(Fields),
+    List(Vec<Fields>, Span),
+}
+
+// List of `key: value` pairs, either the whole macro input or a braced block
+struct Fields {
+    entries: Vec<(Ident, Value)>,
+    span: Span,
+}
+
+impl Parse for Fields {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let span = input.span();
+        let mut entries = Vec::new();
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![:]>()?;
+            let value = input.parse()?;
+            if entries.iter().any(|(existing, _)| *existing == key) {
+                return Err(syn::Error::new(
+                    key.span(),
+                    format!("duplicate key `{}`", key),
+                ));
+            }
+            entries.push((key, value));
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+        Ok(Self { entries, span })
+    }
+}
+
+impl Parse for Value {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(token::Brace) {
+            let content;
+            braced!(content in input);
+            Ok(Value::Struct(content.parse()?))
+        } else if input.peek(token::Bracket) {
+            let span = input.span();
+            let content;
+            bracketed!(content in input);
+            let items = Punctuated::<BracedFields, Token![,]>::parse_terminated(&content)?;
+            Ok(Value::List(
+                items.into_iter().map(|item| item.0).collect(),
+                span,
+            ))
+        } else if input.peek(LitBool) {
+            Ok(Value::Bool(input.parse()?))
+        } else {
+            Ok(Value::Int(input.parse()?))
+        }
+    }
+}
+
+struct BracedFields(Fields);
+
+impl Parse for BracedFields {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let content;
+        braced!(content in input);
+        Ok(Self(content.parse()?))
+    }
+}
+
+impl Fields {
+    fn take(&mut self, key: &str) -> Option<(Ident, Value)> {
+        let index = self.entries.iter().position(|(ident, _)| ident == key)?;
+        Some(self.entries.remove(index))
+    }
+
+    fn int(&mut self, key: &str) -> Option<(u64, Span)> {
+        match self.take(key)? {
+            (_, Value::Int(lit)) => match lit.base10_parse::<u64>() {
+                Ok(value) => Some((value, lit.span())),
+                Err(err) => abort!(lit, "{}", err),
+            },
+            (ident, _) => abort!(ident, "`{}` has to be an integer", key),
+        }
+    }
+
+    fn required_int(&mut self, key: &str) -> (u64, Span) {
+        match self.int(key) {
+            Some(value) => value,
+            None => abort!(self.span, "missing `{}`", key),
+        }
+    }
+
+    fn bool(&mut self, key: &str) -> Option<bool> {
+        match self.take(key)? {
+            (_, Value::Bool(lit)) => Some(lit.value),
+            (ident, _) => abort!(ident, "`{}` has to be `true` or `false`", key),
+        }
+    }
+
+    fn required_struct(&mut self, key: &str) -> Fields {
+        match self.take(key) {
+            Some((_, Value::Struct(fields))) => fields,
+            Some((ident, _)) => abort!(ident, "`{}` has to be a block `{{ ... }}`", key),
+            None => abort!(self.span, "missing `{}`", key),
+        }
+    }
+
+    fn list(&mut self, key: &str) -> Option<(Vec<Fields>, Span)> {
+        match self.take(key)? {
+            (_, Value::List(items, span)) => Some((items, span)),
+            (ident, _) => abort!(ident, "`{}` has to be a list `[{{ ... }}, ...]`", key),
+        }
+    }
+
+    // Reject any keys which were not taken
+    fn finish(self) {
+        if let Some((ident, _)) = self.entries.first() {
+            abort!(ident, "unknown key `{}`", ident);
+        }
+    }
+}
+
+// A bank placed by the layout, with location and size in bytes
+#[derive(Clone, Copy)]
+struct Bank {
+    location: u64,
+    size: u64,
+}
+
+struct Layout {
+    bootloader: Bank,
+    boot: Bank,
+    update: Bank,
+    images: Vec<(Bank, Bank)>,
+    history: Option<Bank>,
+    flash_origin: u64,
+    ram: Bank,
+    ram_origin: u64,
+    ram_state: bool,
+    image_header_offset: Option<u64>,
+    erase_size: u64,
+}
+
+// Places banks one after another, starting at the flash origin. Locations are relative to the
+// origin, like the ones of moonboot::hardware::Bank.
+struct Allocator {
+    origin: u64,
+    next: u64,
+    end: u64,
+    erase_size: u64,
+}
+
+impl Allocator {
+    fn allocate(&mut self, name: &str, (kib, span): (u64, Span)) -> Bank {
+        let size = kib_to_bytes(kib, span);
+        if size == 0 {
+            abort!(span, "partition `{}` has a size of zero", name);
+        }
+        if !size.is_multiple_of(self.erase_size) {
+            abort!(
+                span,
+                "partition `{}` is not a multiple of the erase size of {} bytes",
+                name,
+                self.erase_size
+            );
+        }
+        let bank = Bank {
+            location: self.next,
+            size,
+        };
+        self.next = match self.next.checked_add(size) {
+            Some(next) if next <= self.end => next,
+            _ => abort!(
+                span,
+                "partition `{}` at 0x{:x} exceeds the end of the flash at 0x{:x}",
+                name,
+                self.origin + bank.location,
+                self.origin + self.end
+            ),
+        };
+        bank
+    }
+}
+
+// Convert a size given in KiB to bytes, rejecting sizes exceeding the 32 bit address space
+fn kib_to_bytes(kib: u64, span: Span) -> u64 {
+    match kib.checked_mul(1024) {
+        Some(size) if size <= u32::MAX as u64 + 1 => size,
+        _ => abort!(span, "{} KiB exceed the 32 bit address space", kib),
+    }
+}
+
+impl Layout {
+    fn from_fields(fields: &mut Fields) -> Self {
+        let mut flash = fields.required_struct("flash");
+        let (flash_origin, origin_span) = flash.required_int("origin");
+        let (flash_size, flash_span) = flash.required_int("size");
+        let (erase_size, erase_span) = flash.required_int("erase_size");
+        flash.finish();
+
+        if erase_size == 0 {
+            abort!(erase_span, "the erase size must not be zero");
+        }
+        if !flash_origin.is_multiple_of(erase_size) {
+            abort!(
+                origin_span,
+                "the flash origin is not aligned to the erase size of {} bytes",
+                erase_size
+            );
+        }
+        let flash_size = kib_to_bytes(flash_size, flash_span);
+        if flash_origin.saturating_add(flash_size) > u32::MAX as u64 + 1 {
+            abort!(origin_span, "the flash exceeds the 32 bit address space");
+        }
+
+        let mut ram = fields.required_struct("ram");
+        let (ram_origin, ram_span) = ram.required_int("origin");
+        let (ram_size, ram_size_span) = ram.required_int("size");
+        let ram_state = ram.bool("state").unwrap_or(false);
+        ram.finish();
+        let ram_size = kib_to_bytes(ram_size, ram_size_span);
+        if ram_origin.saturating_add(ram_size) > u32::MAX as u64 + 1 {
+            abort!(ram_span, "the RAM exceeds the 32 bit address space");
+        }
+
+        let mut allocator = Allocator {
+            origin: flash_origin,
+            next: 0,
+            end: flash_size,
+            erase_size,
+        };
+        let bootloader = allocator.allocate("bootloader", fields.required_int("bootloader"));
+        let boot_size = fields.required_int("boot");
+        let boot = allocator.allocate("boot", boot_size);
+        let update = allocator.allocate("update", fields.required_int("update"));
+        if boot.size != update.size {
+            abort!(boot_size.1, "partitions `boot` and `update` differ in size");
+        }
+
+        let mut images = Vec::new();
+        if let Some((items, _)) = fields.list("images") {
+            for (index, mut item) in items.into_iter().enumerate() {
+                let name = format!("images[{}]", index);
+                let boot_size = item.required_int("boot");
+                let image_boot = allocator.allocate(&name, boot_size);
+                let image_update = allocator.allocate(&name, item.required_int("update"));
+                item.finish();
+                if image_boot.size != image_update.size {
+                    abort!(
+                        boot_size.1,
+                        "boot and update partition of `{}` differ in size",
+                        name
+                    );
+                }
+                images.push((image_boot, image_update));
+            }
+        }
+
+        let history = fields
+            .int("history")
+            .map(|size| allocator.allocate("history", size));
+
+        // Whether the header fits into the boot partitions is checked by Config::validate
+        let image_header_offset = fields.int("image_header_offset").map(|(offset, span)| {
+            if offset > u32::MAX as u64 {
+                abort!(
+                    span,
+                    "the image header offset exceeds the 32 bit address space"
+                );
+            }
+            offset
+        });
+
+        Self {
+            bootloader,
+            boot,
+            update,
+            images,
+            history,
+            flash_origin,
+            ram: Bank {
+                location: 0,
+                size: ram_size,
+            },
+            ram_origin,
+            ram_state,
+            image_header_offset,
+            erase_size,
+        }
+    }
+}
+
+fn bank_tokens(bank: &Bank) -> TokenStream2 {
+    let location = bank.location as u32;
+    let size = bank.size as u32;
+    quote!(moonboot::hardware::Bank {
+        location: #location,
+        size: #size,
+        memory_unit: moonboot::hardware::MemoryUnit::Internal,
+    })
+}
+
+fn codegen(layout: &Layout) -> TokenStream {
+    let boot_bank = bank_tokens(&layout.boot);
+    let update_bank = bank_tokens(&layout.update);
+    let bootloader_bank = bank_tokens(&layout.bootloader);
+    let ram_bank = bank_tokens(&layout.ram);
+    let image_header_offset = match layout.image_header_offset {
+        Some(offset) => {
+            let offset = offset as u32;
+            quote!(Some(#offset))
+        }
+        None => quote!(None),
+    };
+    let image_count = layout.images.len();
+    let additional_images = layout
+        .images
+        .iter()
+        .enumerate()
+        .map(|(index, (boot, update))| {
+            let boot = bank_tokens(boot);
+            let update = bank_tokens(update);
+            // Too many images are reported by the assertion below instead of failing here
+            quote!(if #index < images.len() {
+                images[#index] = Some(moonboot::hardware::ImageBanks {
+                    boot_bank: #boot,
+                    update_bank: #update,
+                });
+            })
+        });
+    let history_bank = match &layout.history {
+        Some(history) => {
+            let history = bank_tokens(history);
+            quote!(Some(#history))
+        }
+        None => quote!(None),
+    };
+    let flash_origin = layout.flash_origin as u32;
+    let ram_origin = layout.ram_origin as u32;
+    let ram_state = layout.ram_state;
+    let page_size = layout.erase_size as usize;
+
+    quote!(
+        /// Partitioning of this device, generated by `moonboot::partitions!`
+        pub const MOONBOOT_CONFIG: moonboot::hardware::Config = moonboot::hardware::Config {
+            boot_bank: #boot_bank,
+            update_bank: #update_bank,
+            bootloader_bank: #bootloader_bank,
+            ram_bank: #ram_bank,
+            image_header_offset: #image_header_offset,
+            additional_images: {
+                let mut images = [None; moonboot::hardware::MAX_IMAGES - 1];
+                #(#additional_images)*
+                images
+            },
+            history_bank: #history_bank,
+        };
+
+        /// Linker configuration of this device, generated by `moonboot::partitions!`. The
+        /// locations of `MOONBOOT_CONFIG` are relative to these origins.
+        pub const MOONBOOT_LINKER_CONFIG: moonboot::hardware::LinkerConfig =
+            moonboot::hardware::LinkerConfig {
+                flash_origin: #flash_origin,
+                ram_origin: #ram_origin,
+                has_ram_state: #ram_state,
+            };
+
+        /// Erase size of the internal flash, generated by `moonboot::partitions!`
+        pub const MOONBOOT_PAGE_SIZE: usize = #page_size;
+
+        const _: () = assert!(
+            #image_count < moonboot::hardware::MAX_IMAGES,
+            "too many additional images"
+        );
+        const _: () = assert!(
+            MOONBOOT_CONFIG.validate(MOONBOOT_PAGE_SIZE).is_ok(),
+            "invalid partition layout"
+        );
+        const _: () = assert!(
+            !MOONBOOT_LINKER_CONFIG.has_ram_state
+                || MOONBOOT_CONFIG.ram_bank.size as usize >= moonboot::state::STATE_RESERVED_SIZE,
+            "the RAM is too small to reserve the shared state"
+        );
+    )
+    .into()
+}
@@ -0,0 +1,33 @@
+// Safe wrappers around the lifecycle hooks. The symbols are either defined by the handlers marked
+// with the hook attributes, or resolved to the empty defaults in lib.rs by the generated linker
+// script.
+
+use crate::state::UpdateError;
+
+extern "Rust" {
+    fn _moonboots_on_update_start();
+    fn _moonboots_on_exchange_progress(page: u32, total: u32);
+    fn _moonboots_on_update_complete();
+    fn _moonboots_on_revert();
+    fn _moonboots_on_error(error: UpdateError);
+}
+
+pub(super) fn update_start() {
+    unsafe { _moonboots_on_update_start() }
+}
+
+pub(super) fn exchange_progress(page: u32, total: u32) {
+    unsafe { _moonboots_on_exchange_progress(page, total) }
+}
+
+pub(super) fn update_complete() {
+    unsafe { _moonboots_on_update_complete() }
+}
+
+pub(super) fn revert() {
+    unsafe { _moonboots_on_revert() }
+}
+
+pub(super) fn error(error: UpdateError) {
+    unsafe { _moonboots_on_error(error) }
+}
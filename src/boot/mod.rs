@@ -1,26 +1,67 @@
 use crate::{
-    hardware::processor::Processor,
-    hardware::{Bank, Config},
-    state::{ExchangeProgress, State, Update, UpdateError},
+    hardware::processor::{Processor, IMAGE_PREAMBLE_SIZE},
+    hardware::watchdog::{ExchangeEstimate, Feeding, FlashTiming, NoWatchdog, Watchdog},
+    hardware::{Bank, Config, ConfigError, MAX_IMAGES},
+    history::{self, Event},
+    image::{self, ImageHeader},
+    state::{ExchangeProgress, ImageSet, MoonbootState, State, Update, UpdateError},
     Address,
 };
 
 use embedded_storage::Storage;
 
+mod hooks;
+mod policy;
+
+pub use policy::{BootAction, BootContext, BootPolicy, DefaultPolicy};
+
 use crate::log;
 
 #[cfg(feature = "defmt")]
 use defmt::Format;
 
-
 /// Error occured during nemory access
 #[cfg_attr(feature = "use-defmt", derive(Format))]
 #[derive(Debug)]
 enum MemoryError {
     BankSizeNotEqual,
     BankSizeZero,
+    InvalidProgress,
+    InvalidImage,
     ReadFailure,
     WriteFailure,
+    // Writing failed and the original contents of the page could not be restored either
+    RestoreFailure,
+}
+
+/// Position and cause of a failed exchange
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[derive(Debug)]
+struct ExchangeFailure {
+    // Image which could not be exchanged
+    image: u8,
+    // Page of the image which could not be exchanged, all pages before it are exchanged. Unless
+    // the error is RestoreFailure, the page itself holds its original contents.
+    page_index: u32,
+    error: MemoryError,
+}
+
+/// What the bootloader does if exchanging the images of an update fails, e.g. because of a
+/// transient flash error
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryPolicy {
+    /// Store the error and boot whatever is in the boot bank. No further update is installed until
+    /// the application calls `MoonbootManager::clear_error`.
+    GiveUp,
+    /// Repeat every failed read or write of a page up to the given number of times, then give up.
+    /// Pages are kept in RAM while they are exchanged, so a page is never lost by a failed write
+    /// as long as restoring it succeeds.
+    Retry(u8),
+    /// Like [RetryPolicy::Retry], but instead of giving up, exchange everything already exchanged
+    /// back so the previous images are booted. The error is stored nonetheless. Exchanging back is
+    /// not continued after a power loss.
+    RetryThenRevert(u8),
 }
 
 /// Use this from your bootloader application and call boot() to do the magic, reading the current
@@ -29,12 +70,21 @@ pub struct MoonbootBoot<
     InternalMemory: Storage,
     HardwareState: State,
     CPU: Processor, // TODO: Wrap these into a context struct like rubble?
+    WDT: Watchdog,
+    Policy: BootPolicy,
     const INTERNAL_PAGE_SIZE: usize,
 > {
     config: Config,
     internal_memory: InternalMemory,
     state: HardwareState,
     processor: CPU,
+    watchdog: WDT,
+    policy: Policy,
+    // Whether the next history record is the first one of this boot
+    new_boot: bool,
+    // Images reverted during this boot
+    reverted: ImageSet,
+    retry_policy: RetryPolicy,
 }
 
 impl<
@@ -42,7 +92,8 @@ impl<
         HardwareState: State,
         CPU: Processor,
         const INTERNAL_PAGE_SIZE: usize,
-    > MoonbootBoot<InternalMemory, HardwareState, CPU, INTERNAL_PAGE_SIZE>
+    >
+    MoonbootBoot<InternalMemory, HardwareState, CPU, NoWatchdog, DefaultPolicy, INTERNAL_PAGE_SIZE>
 {
     /// create a new instance of the bootloader
     pub fn new(
@@ -50,21 +101,118 @@ impl<
         internal_memory: InternalMemory,
         state: HardwareState,
         processor: CPU,
-    ) -> MoonbootBoot<InternalMemory, HardwareState, CPU, INTERNAL_PAGE_SIZE> {
-        Self {
+    ) -> Result<Self, ConfigError> {
+        config.validate(INTERNAL_PAGE_SIZE)?;
+        config.validate_capacity(internal_memory.capacity())?;
+
+        Ok(Self {
             config,
             internal_memory,
             state,
             processor,
+            watchdog: NoWatchdog,
+            policy: DefaultPolicy,
+            new_boot: true,
+            reverted: ImageSet::empty(),
+            retry_policy: RetryPolicy::GiveUp,
+        })
+    }
+}
+
+impl<
+        InternalMemory: Storage,
+        HardwareState: State,
+        CPU: Processor,
+        Policy: BootPolicy,
+        const INTERNAL_PAGE_SIZE: usize,
+    > MoonbootBoot<InternalMemory, HardwareState, CPU, NoWatchdog, Policy, INTERNAL_PAGE_SIZE>
+{
+    /// Feed the given watchdog between flash operations while exchanging images, see
+    /// [Watchdog]
+    pub fn with_watchdog<WDT: Watchdog>(
+        self,
+        watchdog: WDT,
+    ) -> MoonbootBoot<InternalMemory, HardwareState, CPU, WDT, Policy, INTERNAL_PAGE_SIZE> {
+        MoonbootBoot {
+            config: self.config,
+            internal_memory: self.internal_memory,
+            state: self.state,
+            processor: self.processor,
+            watchdog,
+            policy: self.policy,
+            new_boot: self.new_boot,
+            reverted: self.reverted,
+            retry_policy: self.retry_policy,
         }
     }
+}
+
+impl<
+        InternalMemory: Storage,
+        HardwareState: State,
+        CPU: Processor,
+        WDT: Watchdog,
+        const INTERNAL_PAGE_SIZE: usize,
+    > MoonbootBoot<InternalMemory, HardwareState, CPU, WDT, DefaultPolicy, INTERNAL_PAGE_SIZE>
+{
+    /// Decide what to do on every boot with the given policy instead of following the update
+    /// state written by the application, see [BootPolicy]
+    pub fn with_policy<Policy: BootPolicy>(
+        self,
+        policy: Policy,
+    ) -> MoonbootBoot<InternalMemory, HardwareState, CPU, WDT, Policy, INTERNAL_PAGE_SIZE> {
+        MoonbootBoot {
+            config: self.config,
+            internal_memory: self.internal_memory,
+            state: self.state,
+            processor: self.processor,
+            watchdog: self.watchdog,
+            policy,
+            new_boot: self.new_boot,
+            reverted: self.reverted,
+            retry_policy: self.retry_policy,
+        }
+    }
+}
+
+impl<
+        InternalMemory: Storage,
+        HardwareState: State,
+        CPU: Processor,
+        WDT: Watchdog,
+        Policy: BootPolicy,
+        const INTERNAL_PAGE_SIZE: usize,
+    > MoonbootBoot<InternalMemory, HardwareState, CPU, WDT, Policy, INTERNAL_PAGE_SIZE>
+{
+    /// Set what to do if exchanging the images of an update fails. Defaults to
+    /// [RetryPolicy::GiveUp].
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
 
     /// Destroy this instance of the bootloader and return access to the hardware peripheral
-    pub fn destroy(self) -> (InternalMemory, HardwareState, CPU) {
-        (self.internal_memory, self.state, self.processor)
+    pub fn destroy(self) -> (InternalMemory, HardwareState, CPU, WDT, Policy) {
+        (
+            self.internal_memory,
+            self.state,
+            self.processor,
+            self.watchdog,
+            self.policy,
+        )
+    }
+
+    /// Estimate the worst case duration of exchanging the given images, see
+    /// [Config::estimate_exchange]
+    pub fn estimate_exchange(&self, images: ImageSet, timing: FlashTiming) -> ExchangeEstimate {
+        self.config
+            .estimate_exchange(images, INTERNAL_PAGE_SIZE, timing)
     }
 
-    /// Execute the update and boot logic of the bootloader
+    /// Execute the update and boot logic of the bootloader. Returns an error if the state could
+    /// not be stored, the [BootPolicy] halts or neither the boot nor the update bank contain a
+    /// bootable image, in which case you might want to enter a recovery mode instead of
+    /// resetting.
     pub fn boot(&mut self) -> Result<void::Void, ()> {
         // TODO: consider error handling
         log::info!("Booting with moonboot!");
@@ -75,14 +223,33 @@ impl<
 
         log::info!("Old State: {:?}", state);
 
-        // Step 1: Do things according to update state
-        state.update = match state.update {
-            Update::None => self.handle_none(),
-            Update::Request(bank) => self.handle_request(bank),
-            Update::Revert(bank) => self.handle_revert(bank),
-            Update::Exchanging(progress) => self.handle_exchanging(progress),
-            Update::Error(err) => Update::Error(err),
+        // Step 1: Decide what to do and do it
+        let context = self.context(&state);
+        let action = self.policy.decide(&context);
+        log::info!("Boot policy decided to {:?}", action);
+
+        // Any exchange changes the contents of the update banks, so downloads cannot be resumed
+        if !matches!(action, BootAction::Boot | BootAction::Halt) {
+            state.download = Default::default();
+        }
+
+        let previous = state.update;
+        state.update = match action {
+            BootAction::Boot => self.handle_boot(state.update),
+            BootAction::Swap(images) => self.handle_request(images),
+            BootAction::Revert(images) => self.handle_revert(images),
+            BootAction::Rollback(images) => self.handle_rollback(images),
+            BootAction::Recover => self.handle_recover(state.update),
+            BootAction::Halt => state.update,
+        };
+        state.boots = if state.update == previous {
+            state.boots.saturating_add(1)
+        } else {
+            0
         };
+        // Confirmations only ever refer to the images started by the previous boot
+        state.confirmed = ImageSet::empty();
+        state.reverted = self.reverted;
 
         // TODO: Handle Progress Variable in state to recover from power loss
 
@@ -90,37 +257,142 @@ impl<
 
         // Step 2: Update state of Bootloader
         self.state.write(state)?;
+        if action == BootAction::Halt {
+            log::error!("Boot policy halted the bootloader!");
+            return Err(());
+        }
 
-        // Step 3: Jump to new or unchanged firmware
+        // Step 3: Make sure we do not jump into an erased or broken image
+        if !self.is_image_valid(self.config.boot_bank) {
+            self.recover_boot_image()?;
+        }
+
+        // Step 4: Jump to new or unchanged firmware
         self.jump_to_firmware();
     }
 
-    // Handle a Update::None Request (no op effectively)
-    fn handle_none(&mut self) -> Update {
-        // No update requested -> Nothing to do
+    // Gather the state and the image headers of all banks for the boot policy
+    fn context<'a>(&mut self, state: &'a MoonbootState) -> BootContext<'a> {
+        let mut boot_headers = [None; MAX_IMAGES];
+        let mut update_headers = [None; MAX_IMAGES];
+        if let Some(header_offset) = self.config.image_header_offset {
+            for image in 0..MAX_IMAGES {
+                if let Some(banks) = self.config.image_banks(image as u8) {
+                    boot_headers[image] = image::read_header(
+                        &mut self.internal_memory,
+                        banks.boot_bank,
+                        header_offset,
+                    )
+                    .ok();
+                    update_headers[image] = image::read_header(
+                        &mut self.internal_memory,
+                        banks.update_bank,
+                        header_offset,
+                    )
+                    .ok();
+                }
+            }
+        }
+
+        BootContext {
+            state,
+            boot_headers,
+            update_headers,
+            boots: state.boots,
+        }
+    }
+
+    // Boot without changing anything (no op effectively)
+    fn handle_boot(&mut self, update: Update) -> Update {
         log::info!("Nothing to do, jumping straight to firmware!");
-        Update::None
+        update
+    }
+
+    // Continue the exchange stored in the update state, if there is one
+    fn handle_recover(&mut self, update: Update) -> Update {
+        match update {
+            Update::Exchanging(progress) => self.handle_exchanging(progress),
+            _ => {
+                log::error!("Recovery requested, but no exchange was interrupted!");
+                self.record(Event::Error(UpdateError::InvalidState));
+                Update::Error(UpdateError::InvalidState)
+            }
+        }
     }
 
     // Handle an Update::Request state, replacing the old firmware with the new one
-    fn handle_request(&mut self, new_firmware: Bank) -> Update {
-        log::info!("Update to firmware image {:?} requested.", new_firmware);
+    fn handle_request(&mut self, images: ImageSet) -> Update {
+        log::info!("Update of images {:?} requested.", images);
+
+        // Installing a broken download would only revert it right away
+        if let Err(err) = self.check_update_banks(images, UpdateError::InvalidSignature) {
+            log::error!("Update banks hold no valid new images, not updating!");
+            return Update::Error(err);
+        }
+
+        if !self.check_dependencies(images) {
+            log::error!("Update would break the dependencies between images, not updating!");
+            self.record(Event::Error(UpdateError::DependencyMismatch));
+            return Update::Error(UpdateError::DependencyMismatch);
+        }
 
-        self.exchange_firmwares(new_firmware, true)
+        self.exchange_firmwares(images, true)
     }
 
     // Handle a revert request because booting of the new firmware failed
-    fn handle_revert(&mut self, revert_firmware: Bank) -> Update {
+    fn handle_revert(&mut self, images: ImageSet) -> Update {
         // Exchange failed update firmware with old firmware image, on success return None so
         // firmware and bootloader functions as usual
         log::warn!(
             "Firmware did not reset state from Revert to None, something went wrong after update!"
         );
-        log::info!(
-            "Reverting to previous firmware image {:?}.",
-            revert_firmware
-        );
-        self.exchange_firmwares(revert_firmware, false)
+        log::info!("Reverting to previous firmware images {:?}.", images);
+        self.exchange_firmwares(images, false)
+    }
+
+    // Handle a rollback requested by the application, exchanging the confirmed images back to
+    // the previous ones if those are still intact
+    fn handle_rollback(&mut self, images: ImageSet) -> Update {
+        log::info!("Rollback of images {:?} requested.", images);
+
+        if let Err(err) = self.check_update_banks(images, UpdateError::NoPreviousImage) {
+            return Update::Error(err);
+        }
+
+        self.exchange_firmwares(images, false)
+    }
+
+    // Check whether the update banks of all images hold valid images before exchanging them,
+    // recording `invalid` as error otherwise
+    fn check_update_banks(
+        &mut self,
+        images: ImageSet,
+        invalid: UpdateError,
+    ) -> Result<(), UpdateError> {
+        for image in images.iter() {
+            let update_bank = match self.config.image_banks(image) {
+                Some(banks) => banks.update_bank,
+                None => {
+                    log::error!("An invalid image index has been specified!");
+                    self.record(Event::Error(UpdateError::InvalidImageIndex));
+                    return Err(UpdateError::InvalidImageIndex);
+                }
+            };
+
+            // Only the main image is booted by this processor
+            let valid = if image == 0 {
+                self.is_image_valid(update_bank)
+            } else {
+                self.is_header_valid(update_bank)
+            };
+            if !valid {
+                log::error!("Update bank of image {} holds no valid image!", image);
+                self.record(Event::Error(invalid));
+                return Err(invalid);
+            }
+        }
+
+        Ok(())
     }
 
     // Handle a case of power interruption or similar, which lead to a exchange_banks being
@@ -130,169 +402,508 @@ impl<
             "Firmware Update was interrupted! Trying to recover with exchange operation: {:?}",
             progress
         );
+        self.record(Event::ExchangeInterrupted {
+            image: progress.image,
+            page_index: progress.page_index,
+        });
 
-        let exchange_result =
-            self.exchange_banks_with_start(progress.a, progress.b, progress.page_index);
-
-        if exchange_result.is_ok() {
-            let state = self.state.read().update;
-            match state {
-                Update::Exchanging(progress) => {
-                    if progress.recovering {
-                        Update::None
-                    } else {
-                        Update::Revert(progress.a)
-                    }
-                }
-                _ => Update::Error(UpdateError::InvalidState),
-            }
-        } else {
+        let exchange_result = self.exchange_images(
+            progress.images,
+            progress.recovering,
+            progress.image,
+            progress.page_index,
+        );
+
+        self.finish_exchange(progress.images, progress.recovering, exchange_result)
+    }
+
+    // Exchange the bootable images with the ones in their update banks. Returns Revert on success
+    // if with_failsafe_revert is true, returns None if with_failsafe_revert ist false
+    fn exchange_firmwares(&mut self, images: ImageSet, with_failsafe_revert: bool) -> Update {
+        if images.is_empty()
+            || !images.is_subset(ImageSet::first(MAX_IMAGES as u8))
+            || images
+                .iter()
+                .any(|image| self.config.image_banks(image).is_none())
+        {
+            log::error!("An invalid image index has been specified during update or revert!");
+            self.record(Event::Error(UpdateError::InvalidImageIndex));
+            return Update::Error(UpdateError::InvalidImageIndex);
+        }
+
+        // Try to exchange the firmware images
+        if with_failsafe_revert {
+            hooks::update_start();
+        }
+        self.record(Event::ExchangeStarted(images));
+        let exchange_result = self.exchange_images(images, !with_failsafe_revert, 0, 0);
+        self.finish_exchange(images, !with_failsafe_revert, exchange_result)
+    }
+
+    // Determine the new update state after exchanging images, exchanging back according to the
+    // retry policy if it failed
+    fn finish_exchange(
+        &mut self,
+        images: ImageSet,
+        recovering: bool,
+        exchange_result: Result<(), ExchangeFailure>,
+    ) -> Update {
+        // Failed flash operations were already retried while exchanging
+        if let Err(failure) = exchange_result {
             log::error!(
-                "Could not recover from failed update, Error: {:?}",
-                exchange_result
+                "Failed to exchange firmware images due to a hardware error: {:?}",
+                failure
             );
-            Update::Error(UpdateError::ImageExchangeFailed)
+            self.record(Event::Error(UpdateError::ImageExchangeFailed));
+
+            // Reverting a revert would boot the images which failed before
+            if matches!(self.retry_policy, RetryPolicy::RetryThenRevert(_)) && !recovering {
+                self.unwind_exchange(images, failure);
+            }
+            return Update::Error(UpdateError::ImageExchangeFailed);
+        }
+
+        if recovering {
+            // Reverting to the new firmware, boot as usual to let the firmware try an
+            // update again
+            self.record(Event::RevertExecuted(images));
+            self.reverted = images;
+            Update::None
+        } else {
+            self.record(Event::ExchangeCompleted(images));
+            // Update Firmware Update State to revert. The Application will set this to
+            // None on successful boot. If we go into the bootloader again and this is still
+            // set, something is wrong with the new application, so we will revert!
+            Update::Revert(images)
         }
     }
 
-    // Revert the bootable image with the image in index new_firmware. Returns Revert on success if
-    // with_failsafe_revert is true, returns None if with_failsafe_revert ist false
-    fn exchange_firmwares(&mut self, new: Bank, with_failsafe_revert: bool) -> Update {
-        let old = self.config.boot_bank;
-        // An update from new_firmware to the bootable firmware was requested.
-        if new != old
-        // TODO: sanity check firmware is not updated with itself && new_firmware != self.hardware.bootable_image
-        {
+    // Exchange all pages exchanged before the given failure back. The error is stored first, so
+    // an interrupted unwinding is not mistaken for an interrupted exchange.
+    fn unwind_exchange(&mut self, images: ImageSet, failure: ExchangeFailure) {
+        log::warn!("Exchanging images {:?} back after failed update", images);
+
+        let mut state = self.state.read();
+        state.update = Update::Error(UpdateError::ImageExchangeFailed);
+        if self.state.write(state).is_err() {
+            log::error!("Could not store error, not exchanging back.");
+            return;
+        }
+
+        let mut unwound = ImageSet::empty();
+        for image in images.iter().filter(|image| *image <= failure.image) {
+            let banks = match self.config.image_banks(image) {
+                Some(banks) => banks,
+                None => return,
+            };
+            let pages = if image == failure.image {
+                if let MemoryError::RestoreFailure = failure.error {
+                    log::error!(
+                        "Page {} of image {} was lost, the image stays corrupted",
+                        failure.page_index,
+                        image
+                    );
+                }
+                failure.page_index
+            } else {
+                self.exchanged_pages(banks.update_bank, banks.boot_bank)
+            };
+
+            for page_index in 0..pages {
+                if let Err(err) = self.exchange_page(banks.update_bank, banks.boot_bank, page_index)
+                {
+                    log::error!(
+                        "Could not exchange page {} of image {} back: {:?}",
+                        page_index,
+                        image,
+                        err
+                    );
+                    return;
+                }
+            }
+            unwound = unwound.with(image);
+        }
+
+        self.record(Event::RevertExecuted(unwound));
+        self.reverted = unwound;
+    }
+
+    // Append an event to the update history, if one is configured. Failing to do so must not
+    // prevent the update, so errors are only logged. Every outcome with a lifecycle hook is
+    // recorded, so the hooks are invoked from here as well.
+    fn record(&mut self, event: Event) {
+        match event {
+            Event::ExchangeCompleted(_) => hooks::update_complete(),
+            Event::RevertExecuted(_) => hooks::revert(),
+            Event::Error(err) => hooks::error(err),
+            _ => {}
+        }
+
+        if let Some(bank) = self.config.history_bank {
+            // Scanning for the newest record reads the whole bank
+            let mut storage = Feeding {
+                storage: &mut self.internal_memory,
+                watchdog: &mut self.watchdog,
+            };
+            match history::append(&mut storage, bank, event, self.new_boot) {
+                Ok(record) => {
+                    log::trace!("Recorded {:?}", record);
+                    self.new_boot = false;
+                }
+                Err(_) => log::warn!("Could not record {:?} in history", event),
+            }
+        }
+    }
+
+    // Exchange boot and update bank of every image in images, starting at the given page of the
+    // given image. Images are always exchanged in ascending order, so an interrupted exchange can
+    // be continued.
+    fn exchange_images(
+        &mut self,
+        images: ImageSet,
+        recovering: bool,
+        start_image: u8,
+        start_index: u32,
+    ) -> Result<(), ExchangeFailure> {
+        for image in images.iter().filter(|image| *image >= start_image) {
+            let banks = self.config.image_banks(image).ok_or(ExchangeFailure {
+                image,
+                page_index: 0,
+                error: MemoryError::InvalidImage,
+            })?;
+
             log::info!(
                 "Exchanging bootable firmware image slot (address: 0x{:x}, size: {}K) with image (address: 0x{:x}, size: {}K).",
-                old.location,
-                old.size / 1024,
-                new.location,
-                new.size / 1024
+                banks.boot_bank.location,
+                banks.boot_bank.size / 1024,
+                banks.update_bank.location,
+                banks.update_bank.size / 1024
             );
 
-            // Try to exchange the firmware images
-            let exchange_result = self.exchange_banks(new, old);
-            if exchange_result.is_ok() {
-                if with_failsafe_revert {
-                    // Update Firmware Update State to revert. The Application will set this to
-                    // None on successful boot. If we go into the bootloader again and this is still
-                    // set, something is wrong with the new application, so we will revert!
-                    Update::Revert(new)
-                } else {
-                    // Reverting to the new firmware, boot as usual to let the firmware try an
-                    // update again
-                    Update::None
+            self.exchange_banks_with_start(ExchangeProgress {
+                a: banks.update_bank,
+                b: banks.boot_bank,
+                page_index: if image == start_image { start_index } else { 0 },
+                recovering,
+                image,
+                images,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // Check whether every image to be installed finds the versions of the images it depends on
+    // after the update. Without image headers, there is nothing to check.
+    fn check_dependencies(&mut self, images: ImageSet) -> bool {
+        let header_offset = match self.config.image_header_offset {
+            Some(header_offset) => header_offset,
+            None => return true,
+        };
+
+        for image in images.iter() {
+            let banks = match self.config.image_banks(image) {
+                Some(banks) => banks,
+                None => return false,
+            };
+            let dependency = match image::read_header(
+                &mut self.internal_memory,
+                banks.update_bank,
+                header_offset,
+            ) {
+                Ok(ImageHeader {
+                    dependency: Some(dependency),
+                    ..
+                }) => dependency,
+                // Images without valid header are rejected before booting anyways
+                _ => continue,
+            };
+
+            // The image depended upon is either updated as well or stays the same
+            let dependency_bank = match self.config.image_banks(dependency.image) {
+                Some(banks) if images.contains(dependency.image) => banks.update_bank,
+                Some(banks) => banks.boot_bank,
+                None => return false,
+            };
+            let version =
+                image::read_header(&mut self.internal_memory, dependency_bank, header_offset)
+                    .map(|header| header.version);
+
+            match version {
+                Ok(version) if version >= dependency.min_version => {}
+                _ => {
+                    log::error!(
+                        "Image {} requires {:?}, found {:?}",
+                        image,
+                        dependency,
+                        version
+                    );
+                    return false;
                 }
-            } else {
-                log::error!(
-                    "Failed to exchange firmware images due to a hardware error: {:?}",
-                    exchange_result
-                );
-                Update::Error(UpdateError::ImageExchangeFailed)
             }
+        }
+
+        true
+    }
+
+    // The boot bank does not contain a bootable image. Try to restore the image from the update
+    // bank, otherwise return an error so the caller can enter its recovery mode.
+    fn recover_boot_image(&mut self) -> Result<(), ()> {
+        log::error!("Boot bank does not contain a valid image!");
+
+        // If the main image was just updated, revert everything updated alongside as well
+        let images = match self.state.read().update {
+            Update::Revert(images) => images,
+            _ => ImageSet::MAIN,
+        };
+
+        if !self.is_image_valid(self.config.update_bank) {
+            log::error!("Update bank does not contain a valid image either, giving up.");
+            return Err(());
+        }
+
+        log::info!("Reverting to images {:?} from update banks.", images);
+
+        // Store the revert first so an interrupted exchange is recovered from as a revert
+        let mut state = self.state.read();
+        state.update = Update::Revert(images);
+        self.state.write(state)?;
+
+        let mut state = self.state.read();
+        state.update = self.exchange_firmwares(images, false);
+        state.confirmed = ImageSet::empty();
+        state.reverted = self.reverted;
+        self.state.write(state)?;
+
+        if self.is_image_valid(self.config.boot_bank) {
+            Ok(())
         } else {
-            log::error!("An invalid image index has been specified during update or revert!");
-            Update::Error(UpdateError::InvalidImageIndex)
+            log::error!("Boot bank is still invalid after revert, giving up.");
+            Err(())
+        }
+    }
+
+    // Check whether the image stored in the given bank can be booted
+    fn is_image_valid(&mut self, bank: Bank) -> bool {
+        let mut preamble = [0_u8; IMAGE_PREAMBLE_SIZE];
+        if self
+            .internal_memory
+            .read(bank.location, &mut preamble)
+            .is_err()
+        {
+            log::error!("Could not read image preamble of {:?}", bank);
+            return false;
+        }
+
+        // Images are always executed from the boot bank, no matter where they are stored now
+        if !self
+            .processor
+            .is_bootable(&self.config, &self.config.boot_bank, &preamble)
+        {
+            log::warn!("Image in {:?} is not bootable", bank);
+            return false;
         }
+
+        self.is_header_valid(bank)
     }
 
-    fn exchange_banks(&mut self, a: Bank, b: Bank) -> Result<(), MemoryError> {
-        self.exchange_banks_with_start(a, b, 0)
+    // Check the image stored in the given bank against its header, if image headers are used
+    fn is_header_valid(&mut self, bank: Bank) -> bool {
+        if let Some(header_offset) = self.config.image_header_offset {
+            let mut buf = [0_u8; INTERNAL_PAGE_SIZE];
+            // Hashing the image reads the whole bank a page at a time
+            let mut storage = Feeding {
+                storage: &mut self.internal_memory,
+                watchdog: &mut self.watchdog,
+            };
+            let result = image::read_header(&mut storage, bank, header_offset).and_then(|header| {
+                image::verify(&mut storage, bank, header_offset, &header, &mut buf)
+            });
+
+            if let Err(err) = result {
+                log::warn!("Image in {:?} failed validation: {:?}", bank, err);
+                return false;
+            }
+        }
+
+        true
     }
 
     fn exchange_banks_with_start(
         &mut self,
-        a: Bank,
-        b: Bank,
-        start_index: u32,
-    ) -> Result<(), MemoryError> {
-        // TODO: Sanity Check start_index
+        progress: ExchangeProgress,
+    ) -> Result<(), ExchangeFailure> {
+        let ExchangeProgress {
+            a,
+            b,
+            page_index: start_index,
+            image,
+            ..
+        } = progress;
+        let failure = |page_index, error| ExchangeFailure {
+            image,
+            page_index,
+            error,
+        };
+
         if a.size != b.size {
-            return Err(MemoryError::BankSizeNotEqual);
+            return Err(failure(start_index, MemoryError::BankSizeNotEqual));
         }
 
         if a.size == 0 || b.size == 0 {
-            return Err(MemoryError::BankSizeZero);
+            return Err(failure(start_index, MemoryError::BankSizeZero));
+        }
+
+        let pages = self.exchanged_pages(a, b);
+        if start_index > pages {
+            // Corrupted progress, at most the whole bank can have been exchanged
+            return Err(failure(pages, MemoryError::InvalidProgress));
         }
 
-        let size = a.size; // Both are equal
+        let mut skipped = 0;
+        for page_index in start_index..pages {
+            let exchanged = self
+                .exchange_page(a, b, page_index)
+                .map_err(|err| failure(page_index, err))?;
+
+            // Store the exchange progress, so the exchange continues after this page. Continuing
+            // at an earlier page after skipping identical ones does no harm, as they are still
+            // identical.
+            if exchanged {
+                let mut state = self.state.read();
+                state.update = Update::Exchanging(ExchangeProgress {
+                    page_index: page_index + 1,
+                    ..progress
+                });
+                // TODO: Ignore the error here?
+                let _ = self.state.write(state);
+            } else {
+                skipped += 1;
+            }
 
-        let full_pages = size / INTERNAL_PAGE_SIZE as Address;
-        let remaining_page_length = size as usize % INTERNAL_PAGE_SIZE;
+            hooks::exchange_progress(page_index + 1, pages);
+        }
+        log::info!(
+            "Exchanged {} pages of image {}, skipped {} identical ones",
+            pages - start_index,
+            image,
+            skipped
+        );
+
+        Ok(())
+    }
+
+    // Number of pages of the equally sized banks a and b to exchange. If both contain an image
+    // with a valid header, the pages past the end of the larger image are left alone. The headers
+    // are exchanged along with the images, so the result does not change during the exchange.
+    fn exchanged_pages(&mut self, a: Bank, b: Bank) -> u32 {
+        let mut size = a.size;
+        if let Some(header_offset) = self.config.image_header_offset {
+            let header_a = image::read_header(&mut self.internal_memory, a, header_offset);
+            let header_b = image::read_header(&mut self.internal_memory, b, header_offset);
+            if let (Ok(header_a), Ok(header_b)) = (header_a, header_b) {
+                size = core::cmp::min(core::cmp::max(header_a.size, header_b.size), a.size);
+            }
+        }
+
+        // The last page might only be partially used by the bank
+        size.div_ceil(INTERNAL_PAGE_SIZE as Address)
+    }
+
+    // Exchange a single page of the equally sized banks a and b. Identical pages are not written,
+    // returns whether the page was written. Failed flash operations are retried according to the
+    // retry policy. If writing fails for good, the original contents of both pages are restored
+    // from RAM, so the page can be exchanged again later.
+    fn exchange_page(&mut self, a: Bank, b: Bank, page_index: u32) -> Result<bool, MemoryError> {
+        let offset = page_index * INTERNAL_PAGE_SIZE as Address;
+        let length = core::cmp::min(INTERNAL_PAGE_SIZE as Address, a.size - offset) as usize;
+        let a_address = a.location + offset;
+        let b_address = b.location + offset;
 
         let mut page_a_buf = [0_u8; INTERNAL_PAGE_SIZE];
         let mut page_b_buf = [0_u8; INTERNAL_PAGE_SIZE];
         // can we reduce this to 1 buf and fancy operations?
         // probably not with the read/write API.
         // classic memory exchange problem :)
+        let page_a = &mut page_a_buf[..length];
+        let page_b = &mut page_b_buf[..length];
 
-        // Set this in the exchanging part to know whether we are in a recovery process from a
-        // failed update or on the initial update
-        let recovering = matches!(self.state.read().update, Update::Revert(_));
-
-        // TODO: Fix
-        let a_location = a.location;
-        let b_location = b.location;
-
-        for page_index in start_index..full_pages {
-            let offset = page_index * INTERNAL_PAGE_SIZE as u32;
-            log::trace!(
-                "Exchange: Page {}, from a ({}) to b ({})",
-                page_index,
-                a_location + offset,
-                b_location + offset
-            );
-            self.internal_memory
-                .read(a_location + offset, &mut page_a_buf)
-                .map_err(|_| MemoryError::ReadFailure)?;
-            self.internal_memory
-                .read(b_location + offset, &mut page_b_buf)
-                .map_err(|_| MemoryError::ReadFailure)?;
-            self.internal_memory
-                .write(a_location + offset, &page_b_buf)
-                .map_err(|_| MemoryError::WriteFailure)?;
-            self.internal_memory
-                .write(b_location + offset, &page_a_buf)
-                .map_err(|_| MemoryError::WriteFailure)?;
-
-            // Store the exchange progress
-            let mut state = self.state.read();
-            state.update = Update::Exchanging(ExchangeProgress {
-                a,
-                b,
-                recovering,
-                page_index,
+        log::trace!(
+            "Exchange: Page {}, from a ({}) to b ({})",
+            page_index,
+            a_address,
+            b_address
+        );
+        self.retrying(MemoryError::ReadFailure, |memory| {
+            memory.read(a_address, page_a).map_err(|_| ())
+        })?;
+        self.retrying(MemoryError::ReadFailure, |memory| {
+            memory.read(b_address, page_b).map_err(|_| ())
+        })?;
+        if page_a == page_b {
+            return Ok(false);
+        }
+
+        let written = self
+            .retrying(MemoryError::WriteFailure, |memory| {
+                memory.write(a_address, page_b).map_err(|_| ())
+            })
+            .and_then(|_| {
+                self.retrying(MemoryError::WriteFailure, |memory| {
+                    memory.write(b_address, page_a).map_err(|_| ())
+                })
+            });
+        if let Err(err) = written {
+            // Only the buffers hold the original contents now
+            let restored = self
+                .retrying(MemoryError::WriteFailure, |memory| {
+                    memory.write(a_address, page_a).map_err(|_| ())
+                })
+                .and_then(|_| {
+                    self.retrying(MemoryError::WriteFailure, |memory| {
+                        memory.write(b_address, page_b).map_err(|_| ())
+                    })
+                });
+            return Err(match restored {
+                Ok(()) => err,
+                Err(_) => MemoryError::RestoreFailure,
             });
-            // TODO: Ignore the error here?
-            let _ = self.state.write(state);
-        }
-        // TODO: Fit this into the while loop
-        if remaining_page_length > 0 {
-            let offset = full_pages * INTERNAL_PAGE_SIZE as u32;
-
-            self.internal_memory
-                .read(
-                    a.location + offset,
-                    &mut page_a_buf[0..remaining_page_length],
-                )
-                .map_err(|_| MemoryError::ReadFailure)?;
-            self.internal_memory
-                .read(
-                    b.location + offset,
-                    &mut page_b_buf[0..remaining_page_length],
-                )
-                .map_err(|_| MemoryError::ReadFailure)?;
-            self.internal_memory
-                .write(a.location + offset, &page_a_buf[0..remaining_page_length])
-                .map_err(|_| MemoryError::WriteFailure)?;
-            self.internal_memory
-                .write(b.location + offset, &page_b_buf[0..remaining_page_length])
-                .map_err(|_| MemoryError::WriteFailure)?;
         }
 
-        Ok(())
+        Ok(true)
     }
+
+    // Run a flash operation, feeding the watchdog before and repeating it up to the number of
+    // retries of the retry policy if it fails
+    fn retrying(
+        &mut self,
+        error: MemoryError,
+        mut operation: impl FnMut(&mut InternalMemory) -> Result<(), ()>,
+    ) -> Result<(), MemoryError> {
+        let retries = match self.retry_policy {
+            RetryPolicy::GiveUp => 0,
+            RetryPolicy::Retry(retries) | RetryPolicy::RetryThenRevert(retries) => retries,
+        };
+
+        let mut attempt = 0;
+        loop {
+            self.watchdog.feed();
+            if operation(&mut self.internal_memory).is_ok() {
+                return Ok(());
+            }
+            if attempt == retries {
+                return Err(error);
+            }
+            attempt += 1;
+            log::warn!(
+                "Flash operation failed with {:?}, retrying ({}/{})",
+                error,
+                attempt,
+                retries
+            );
+        }
+    }
+
     // Jump to the firmware image marked as bootable
     fn jump_to_firmware(&mut self) -> ! {
         let app_exec_image = self.config.boot_bank;
@@ -310,3 +921,424 @@ impl<
         self.processor.do_jump(app_address)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::{
+        hardware::MemoryUnit,
+        image::{ImageVersion, IMAGE_HEADER_SIZE},
+    };
+    use core::ops::Range;
+    use embedded_storage::ReadStorage;
+    use std::vec::Vec;
+
+    const PAGE_SIZE: usize = 32;
+    const PAGES: u32 = 16;
+    const BANK_SIZE: Address = PAGES * PAGE_SIZE as Address;
+    const BOOT: Address = BANK_SIZE;
+    const UPDATE: Address = 2 * BANK_SIZE;
+    const MEMORY_SIZE: usize = 3 * BANK_SIZE as usize;
+
+    // Flash kept in RAM, failing the writes with the given indices without changing anything
+    #[derive(Clone)]
+    struct Memory {
+        bytes: [u8; MEMORY_SIZE],
+        writes: u32,
+        failing: Range<u32>,
+    }
+
+    impl ReadStorage for Memory {
+        type Error = ();
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.bytes[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            MEMORY_SIZE
+        }
+    }
+
+    impl Storage for Memory {
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let write = self.writes;
+            self.writes += 1;
+            if self.failing.contains(&write) {
+                return Err(());
+            }
+            let offset = offset as usize;
+            self.bytes[offset..offset + bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    // State keeping every exchange progress written
+    struct Shared {
+        state: MoonbootState,
+        progress: Vec<u32>,
+    }
+
+    impl State for Shared {
+        fn read(&mut self) -> MoonbootState {
+            MoonbootState {
+                update: self.state.update,
+                confirmed: self.state.confirmed,
+                reverted: self.state.reverted,
+                download: self.state.download,
+                boots: self.state.boots,
+            }
+        }
+
+        fn write(&mut self, data: MoonbootState) -> Result<(), ()> {
+            if let Update::Exchanging(progress) = data.update {
+                self.progress.push(progress.page_index);
+            }
+            self.state = data;
+            Ok(())
+        }
+    }
+
+    struct Cpu;
+
+    impl Processor for Cpu {
+        fn do_jump(&mut self, address: Address) -> ! {
+            panic!("Jumped to {:x}", address)
+        }
+
+        fn setup(&mut self, _config: &Config) {}
+    }
+
+    #[export_name = "_moonboots_pre_jump"]
+    fn pre_jump() {}
+    #[export_name = "_moonboots_on_update_start"]
+    fn on_update_start() {}
+    #[export_name = "_moonboots_on_exchange_progress"]
+    fn on_exchange_progress(_page: u32, _total: u32) {}
+    #[export_name = "_moonboots_on_update_complete"]
+    fn on_update_complete() {}
+    #[export_name = "_moonboots_on_revert"]
+    fn on_revert() {}
+    #[export_name = "_moonboots_on_error"]
+    fn on_error(_error: UpdateError) {}
+
+    type Boot = MoonbootBoot<Memory, Shared, Cpu, NoWatchdog, DefaultPolicy, PAGE_SIZE>;
+
+    const MAIN: ImageSet = ImageSet::empty().with(0);
+
+    fn bank(location: Address) -> Bank {
+        Bank {
+            location,
+            size: BANK_SIZE,
+            memory_unit: MemoryUnit::Internal,
+        }
+    }
+
+    fn bootloader(memory: Memory, image_header_offset: Option<Address>) -> Boot {
+        let config = Config {
+            boot_bank: bank(BOOT),
+            update_bank: bank(UPDATE),
+            bootloader_bank: bank(0),
+            ram_bank: bank(0),
+            image_header_offset,
+            additional_images: [None; MAX_IMAGES - 1],
+            history_bank: None,
+        };
+        let state = Shared {
+            state: MoonbootState::default(),
+            progress: Vec::new(),
+        };
+        MoonbootBoot::new(config, memory, state, Cpu).unwrap()
+    }
+
+    // Different images in boot and update bank, except for the given pages
+    fn memory(identical_pages: &[u32]) -> Memory {
+        let mut bytes = [0_u8; MEMORY_SIZE];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (i * 7 + i / 251) as u8;
+        }
+        for page in identical_pages {
+            let boot = (BOOT + page * PAGE_SIZE as Address) as usize;
+            let update = (UPDATE + page * PAGE_SIZE as Address) as usize;
+            bytes.copy_within(boot..boot + PAGE_SIZE, update);
+        }
+        Memory {
+            bytes,
+            writes: 0,
+            failing: 0..0,
+        }
+    }
+
+    fn page(memory: &Memory, bank: Address, page: u32) -> &[u8] {
+        let start = (bank + page * PAGE_SIZE as Address) as usize;
+        &memory.bytes[start..start + PAGE_SIZE]
+    }
+
+    fn is_swapped(memory: &Memory, original: &Memory, index: u32) -> bool {
+        page(memory, BOOT, index) == page(original, UPDATE, index)
+            && page(memory, UPDATE, index) == page(original, BOOT, index)
+    }
+
+    fn is_untouched(memory: &Memory, original: &Memory, index: u32) -> bool {
+        page(memory, BOOT, index) == page(original, BOOT, index)
+            && page(memory, UPDATE, index) == page(original, UPDATE, index)
+    }
+
+    #[test]
+    fn interrupted_exchange_continues_at_stored_progress() {
+        let original = memory(&[]);
+        let mut memory = original.clone();
+        // Power is lost while writing the fourth page, after three pages were exchanged
+        memory.failing = 6..u32::MAX;
+        let mut boot = bootloader(memory, None);
+
+        boot.exchange_firmwares(MAIN, true);
+        let update = boot.state.state.update;
+        match update {
+            Update::Exchanging(progress) => assert_eq!(progress.page_index, 3),
+            _ => panic!("No progress stored: {:?}", update),
+        }
+        assert_eq!(boot.state.progress, [1, 2, 3]);
+        for index in 0..PAGES {
+            let memory = &boot.internal_memory;
+            assert_eq!(
+                is_swapped(memory, &original, index),
+                index < 3,
+                "page {}",
+                index
+            );
+            assert_eq!(
+                is_untouched(memory, &original, index),
+                index >= 3,
+                "page {}",
+                index
+            );
+        }
+
+        boot.internal_memory.failing = 0..0;
+        assert_eq!(boot.handle_recover(update), Update::Revert(MAIN));
+        assert_eq!(
+            boot.state.progress[3..],
+            [4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]
+        );
+        for index in 0..PAGES {
+            assert!(
+                is_swapped(&boot.internal_memory, &original, index),
+                "page {}",
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn identical_pages_are_skipped() {
+        let original = memory(&[1, 2, 15]);
+        let mut boot = bootloader(original.clone(), None);
+
+        assert_eq!(boot.exchange_firmwares(MAIN, true), Update::Revert(MAIN));
+        assert_eq!(boot.internal_memory.writes, 2 * (PAGES - 3));
+        // No progress is stored for the skipped pages
+        assert_eq!(
+            boot.state.progress,
+            [1, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        );
+        for index in 0..PAGES {
+            assert!(
+                is_swapped(&boot.internal_memory, &original, index),
+                "page {}",
+                index
+            );
+        }
+
+        // Continuing after the last stored progress only finds identical pages
+        let writes = boot.internal_memory.writes;
+        let update = Update::Exchanging(ExchangeProgress {
+            a: bank(UPDATE),
+            b: bank(BOOT),
+            page_index: 15,
+            recovering: false,
+            image: 0,
+            images: MAIN,
+        });
+        assert_eq!(boot.handle_recover(update), Update::Revert(MAIN));
+        assert_eq!(boot.internal_memory.writes, writes);
+    }
+
+    #[test]
+    fn exchange_stops_at_the_larger_image() {
+        const HEADER_OFFSET: Address = 0x20;
+        let header = |size| {
+            ImageHeader {
+                version: ImageVersion {
+                    major: 1,
+                    minor: 0,
+                    patch: 0,
+                },
+                size,
+                dependency: None,
+                hash: [0; 32],
+            }
+            .to_bytes()
+        };
+        let mut original = memory(&[]);
+        for (bank, size) in [(BOOT, 0x90), (UPDATE, 0xd0)] {
+            let start = (bank + HEADER_OFFSET) as usize;
+            original.bytes[start..start + IMAGE_HEADER_SIZE].copy_from_slice(&header(size));
+        }
+        let mut boot = bootloader(original.clone(), Some(HEADER_OFFSET));
+
+        assert_eq!(boot.exchange_firmwares(MAIN, true), Update::Revert(MAIN));
+        // The update image ends in the seventh page
+        for index in 0..PAGES {
+            let memory = &boot.internal_memory;
+            assert_eq!(
+                is_swapped(memory, &original, index),
+                index < 7,
+                "page {}",
+                index
+            );
+            assert_eq!(
+                is_untouched(memory, &original, index),
+                index >= 7,
+                "page {}",
+                index
+            );
+        }
+        assert_eq!(boot.state.progress, [1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    // Install the update bank like the bootloader does, up to jumping to the boot bank
+    fn install_update(memory: Memory, retry_policy: RetryPolicy) -> Boot {
+        let mut boot = bootloader(memory, None).with_retry_policy(retry_policy);
+        boot.state.state.update = Update::Request(MAIN);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| boot.boot()));
+        assert!(result.is_err(), "Did not jump to the boot bank");
+        boot
+    }
+
+    #[test]
+    fn failed_write_is_given_up() {
+        let original = memory(&[]);
+        let mut memory = original.clone();
+        // Writing the update bank page of the third page
+        memory.failing = 4..5;
+
+        let boot = install_update(memory, RetryPolicy::GiveUp);
+        assert_eq!(
+            boot.state.state.update,
+            Update::Error(UpdateError::ImageExchangeFailed)
+        );
+        // Both pages are restored, nothing is exchanged after the failure
+        assert_eq!(boot.internal_memory.writes, 7);
+        for index in 0..PAGES {
+            let memory = &boot.internal_memory;
+            assert_eq!(
+                is_swapped(memory, &original, index),
+                index < 2,
+                "page {}",
+                index
+            );
+            assert_eq!(
+                is_untouched(memory, &original, index),
+                index >= 2,
+                "page {}",
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn failed_write_is_retried() {
+        let original = memory(&[]);
+        let mut memory = original.clone();
+        memory.failing = 4..6;
+
+        let boot = install_update(memory.clone(), RetryPolicy::Retry(2));
+        assert_eq!(boot.state.state.update, Update::Revert(MAIN));
+        assert_eq!(boot.internal_memory.writes, 2 * PAGES + 2);
+        for index in 0..PAGES {
+            assert!(
+                is_swapped(&boot.internal_memory, &original, index),
+                "page {}",
+                index
+            );
+        }
+
+        // One retry is not enough
+        let boot = install_update(memory, RetryPolicy::Retry(1));
+        assert_eq!(
+            boot.state.state.update,
+            Update::Error(UpdateError::ImageExchangeFailed)
+        );
+        for index in 0..PAGES {
+            let memory = &boot.internal_memory;
+            assert_eq!(
+                is_swapped(memory, &original, index),
+                index < 2,
+                "page {}",
+                index
+            );
+            assert_eq!(
+                is_untouched(memory, &original, index),
+                index >= 2,
+                "page {}",
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn failed_exchange_is_reverted() {
+        let original = memory(&[]);
+        let mut memory = original.clone();
+        memory.failing = 4..6;
+
+        let boot = install_update(memory, RetryPolicy::RetryThenRevert(1));
+        assert_eq!(
+            boot.state.state.update,
+            Update::Error(UpdateError::ImageExchangeFailed)
+        );
+        assert_eq!(boot.state.state.reverted, MAIN);
+        // The pages exchanged before the failure are exchanged back
+        for index in 0..PAGES {
+            assert!(
+                is_untouched(&boot.internal_memory, &original, index),
+                "page {}",
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn page_lost_by_failed_restore_is_not_booted() {
+        let original = memory(&[]);
+        let mut memory = original.clone();
+        // Writing the boot bank page of the third page and restoring its update bank page
+        memory.failing = 5..7;
+
+        let boot = install_update(memory, RetryPolicy::RetryThenRevert(0));
+        assert_eq!(
+            boot.state.state.update,
+            Update::Error(UpdateError::ImageExchangeFailed)
+        );
+        // The update bank is written first, so only the new image lost its page
+        let memory = &boot.internal_memory;
+        for index in 0..PAGES {
+            assert_eq!(
+                page(memory, BOOT, index),
+                page(&original, BOOT, index),
+                "page {}",
+                index
+            );
+            if index != 2 {
+                assert_eq!(page(memory, UPDATE, index), page(&original, UPDATE, index));
+            }
+        }
+        assert_eq!(page(memory, UPDATE, 2), page(&original, BOOT, 2));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::{
+    hardware::MAX_IMAGES,
+    image::ImageHeader,
+    state::{ImageSet, MoonbootState, Update},
+};
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+/// Everything known to the bootloader when deciding what to do, see [BootPolicy]
+#[derive(Debug, Clone, Copy)]
+pub struct BootContext<'a> {
+    /// State read at the start of this boot
+    pub state: &'a MoonbootState,
+    /// Headers of the images in the boot banks, indexed by image. None if the image does not
+    /// exist, no image header offset is configured or the header is invalid.
+    pub boot_headers: [Option<ImageHeader>; MAX_IMAGES],
+    /// Headers of the images in the update banks, like `boot_headers`
+    pub update_headers: [Option<ImageHeader>; MAX_IMAGES],
+    /// Number of boots the update state has been left unchanged by the bootloader, see
+    /// [MoonbootState::boots]
+    pub boots: u32,
+}
+
+/// Action taken by the bootloader, as decided by a [BootPolicy]
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootAction {
+    /// Boot the image in the boot bank, leaving the update state as it is
+    Boot,
+    /// Install the given images from their update banks. They are reverted unless the
+    /// application confirms them.
+    Swap(ImageSet),
+    /// Exchange the given images back to the previous ones in their update banks
+    Revert(ImageSet),
+    /// Like [BootAction::Revert], but only if the update banks hold valid previous images
+    Rollback(ImageSet),
+    /// Continue the interrupted exchange stored in the state
+    Recover,
+    /// Neither change nor boot anything, `MoonbootBoot::boot` returns an error instead
+    Halt,
+}
+
+/// Decides what the bootloader does on every boot. Implement this for products with special
+/// requirements, e.g. to always boot the newest valid image, and pass it to
+/// `MoonbootBoot::with_policy`.
+pub trait BootPolicy {
+    /// Choose the action for this boot
+    fn decide(&mut self, context: &BootContext) -> BootAction;
+}
+
+/// Follows the update state written by the application, see [Update]
+pub struct DefaultPolicy;
+
+impl BootPolicy for DefaultPolicy {
+    fn decide(&mut self, context: &BootContext) -> BootAction {
+        match context.state.update {
+            Update::None => BootAction::Boot,
+            Update::Request(images) => BootAction::Swap(images),
+            Update::Revert(images) => BootAction::Revert(images),
+            Update::Exchanging(_) => BootAction::Recover,
+            Update::Error(_) => BootAction::Boot,
+            Update::Rollback(images) => BootAction::Rollback(images),
+        }
+    }
+}
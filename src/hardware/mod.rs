@@ -1,6 +1,7 @@
 pub mod processor;
+pub mod watchdog;
 
-use crate::Address;
+use crate::{image::IMAGE_HEADER_SIZE, Address};
 
 #[cfg(feature = "defmt")]
 use defmt::Format;
@@ -27,7 +28,9 @@ pub enum MemoryUnit {
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub struct Bank {
     // TODO: Hide members?
-    /// Starting address of this Bank
+    /// Starting address of this Bank relative to the start of its memory, which is
+    /// `LinkerConfig::flash_origin` or `LinkerConfig::ram_origin`. Flash banks are accessed at
+    /// this address through the flash driver.
     pub location: Address,
     /// Size of this Bank
     pub size: Address, // TODO: Use NonZeroU32 to remove checks?
@@ -35,10 +38,23 @@ pub struct Bank {
     pub memory_unit: MemoryUnit,
 }
 
+/// Maximum number of images managed by moonboot, including the main image
+pub const MAX_IMAGES: usize = 4;
+
+/// Pair of banks holding an image which can be updated
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct ImageBanks {
+    /// bank holding the currently used image
+    pub boot_bank: Bank,
+    /// bank the new image is written to, and the previous image is stored in after an update
+    pub update_bank: Bank,
+}
+
 /// Configuration of your SoCs partitioning
 #[cfg_attr(feature = "defmt", derive(Format))]
 #[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
-#[cfg_attr(feature = "ram-state", derive(Desse, DesseSized))]
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub struct Config {
     /// bank this bootloader jumps to, holds your main firmware
@@ -51,6 +67,180 @@ pub struct Config {
     // pub golden_bank: Bank,
     /// section of RAM of this device
     pub ram_bank: Bank,
+    /// offset of the [crate::image::ImageHeader] from the start of the boot and update bank. If
+    /// set, the bootloader only jumps to images carrying a valid header and hash
+    pub image_header_offset: Option<Address>,
+    /// further images which are updated and reverted together with the main image, e.g. the
+    /// firmware of a second core or a FPGA bitstream. These are referred to by the index 1 and up
+    pub additional_images: [Option<ImageBanks>; MAX_IMAGES - 1],
+    /// bank storing the [crate::history] of update events. If set, bootloader and application
+    /// record every step of an update there, except for requests made through the
+    /// `AsyncMoonbootManager`
+    pub history_bank: Option<Bank>,
+}
+
+/// Inconsistency found by [Config::validate]
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ConfigError {
+    /// The page size is zero
+    InvalidPageSize,
+    /// The bank has a size of zero
+    EmptyBank(Bank),
+    /// Location or size of the bank are not a multiple of the page size
+    Misaligned(Bank),
+    /// The banks share memory
+    Overlap(Bank, Bank),
+    /// Boot and update bank of the image with the given index differ in size, so they cannot be
+    /// exchanged
+    SwapSizeMismatch(u8),
+    /// The bank exceeds the address space or the capacity of its memory unit
+    OutOfMemory(Bank),
+    /// The image header at this offset does not fit into the banks of every image
+    InvalidHeaderOffset(Address),
+}
+
+// Number of flash banks a Config can hold: bootloader, history and boot and update bank of every
+// image
+const MAX_FLASH_BANKS: usize = 2 + 2 * MAX_IMAGES;
+
+impl Config {
+    /// Get the banks of the image with the given index. Index 0 is the main image made up of
+    /// `boot_bank` and `update_bank`, all further indices refer to `additional_images`.
+    pub fn image_banks(&self, image: u8) -> Option<ImageBanks> {
+        match image {
+            0 => Some(ImageBanks {
+                boot_bank: self.boot_bank,
+                update_bank: self.update_bank,
+            }),
+            _ => *self.additional_images.get(image as usize - 1)?,
+        }
+    }
+
+    /// Check the configuration for banks which are empty, not aligned to `page_size`, overlap each
+    /// other or exceed the address space, and for images whose banks cannot be exchanged or hold
+    /// no image header. The
+    /// capacity of the memory units is checked by the constructors of `MoonbootBoot` and
+    /// `MoonbootManager`. Can be evaluated at compile time, e.g.
+    /// `const _: () = assert!(CONFIG.validate(PAGE_SIZE).is_ok());`.
+    pub const fn validate(&self, page_size: usize) -> Result<(), ConfigError> {
+        if page_size == 0 || page_size > Address::MAX as usize {
+            return Err(ConfigError::InvalidPageSize);
+        }
+        let page_size = page_size as Address;
+
+        let mut image = 0;
+        while image < MAX_IMAGES {
+            if let Some(banks) = self.const_image_banks(image) {
+                if banks.boot_bank.size != banks.update_bank.size {
+                    return Err(ConfigError::SwapSizeMismatch(image as u8));
+                }
+                if let Some(header_offset) = self.image_header_offset {
+                    if header_offset as u64 + IMAGE_HEADER_SIZE as u64 > banks.boot_bank.size as u64
+                    {
+                        return Err(ConfigError::InvalidHeaderOffset(header_offset));
+                    }
+                }
+            }
+            image += 1;
+        }
+
+        // Every bank has to be checked for overflow before any end is computed by the overlap check
+        let banks = self.flash_banks();
+        let mut i = 0;
+        while i < MAX_FLASH_BANKS {
+            if let Some(bank) = banks[i] {
+                if bank.size == 0 {
+                    return Err(ConfigError::EmptyBank(bank));
+                }
+                if bank.location.checked_add(bank.size).is_none() {
+                    return Err(ConfigError::OutOfMemory(bank));
+                }
+                // The bootloader and history are not exchanged page by page, but still erased in
+                // whole pages
+                if !bank.location.is_multiple_of(page_size) || !bank.size.is_multiple_of(page_size)
+                {
+                    return Err(ConfigError::Misaligned(bank));
+                }
+            }
+            i += 1;
+        }
+
+        let mut i = 0;
+        while i < MAX_FLASH_BANKS {
+            if let Some(bank) = banks[i] {
+                let mut j = i + 1;
+                while j < MAX_FLASH_BANKS {
+                    if let Some(other) = banks[j] {
+                        if bank.location < other.location + other.size
+                            && other.location < bank.location + bank.size
+                        {
+                            return Err(ConfigError::Overlap(bank, other));
+                        }
+                    }
+                    j += 1;
+                }
+            }
+            i += 1;
+        }
+
+        if self
+            .ram_bank
+            .location
+            .checked_add(self.ram_bank.size)
+            .is_none()
+        {
+            return Err(ConfigError::OutOfMemory(self.ram_bank));
+        }
+
+        Ok(())
+    }
+
+    /// Check every bank stored in the internal memory fits into its `capacity`, in addition to
+    /// [Self::validate]
+    pub const fn validate_capacity(&self, capacity: usize) -> Result<(), ConfigError> {
+        let banks = self.flash_banks();
+        let mut i = 0;
+        while i < MAX_FLASH_BANKS {
+            if let Some(bank) = banks[i] {
+                if (bank.location as u64 + bank.size as u64) > capacity as u64 {
+                    return Err(ConfigError::OutOfMemory(bank));
+                }
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+
+    // Self::image_banks usable in const context
+    const fn const_image_banks(&self, image: usize) -> Option<ImageBanks> {
+        if image == 0 {
+            Some(ImageBanks {
+                boot_bank: self.boot_bank,
+                update_bank: self.update_bank,
+            })
+        } else if image < MAX_IMAGES {
+            self.additional_images[image - 1]
+        } else {
+            None
+        }
+    }
+
+    // All configured banks located in flash
+    const fn flash_banks(&self) -> [Option<Bank>; MAX_FLASH_BANKS] {
+        let mut banks = [None; MAX_FLASH_BANKS];
+        banks[0] = Some(self.bootloader_bank);
+        banks[1] = self.history_bank;
+        let mut image = 0;
+        while image < MAX_IMAGES {
+            if let Some(image_banks) = self.const_image_banks(image) {
+                banks[2 + 2 * image] = Some(image_banks.boot_bank);
+                banks[3 + 2 * image] = Some(image_banks.update_bank);
+            }
+            image += 1;
+        }
+        banks
+    }
 }
 
 /// Configuration for linker scripts
@@ -1,4 +1,10 @@
-use crate::Address;
+use crate::{
+    hardware::{Bank, Config},
+    Address,
+};
+
+/// Number of bytes at the start of an image passed to [Processor::is_bootable]
+pub const IMAGE_PREAMBLE_SIZE: usize = 8;
 
 /// This trait executes an execution jump to the specified startign address.
 /// The implementation is ISA-dependent.
@@ -8,36 +14,434 @@ pub trait Processor {
     /// Hardfault/Panic/Whatever the Processor does then
     fn do_jump(&mut self, address: Address) -> !;
     /// Setup the specified hardware config. Can be used to initialize an MPU for example.
-    fn setup(&mut self, config: &crate::hardware::Config);
+    fn setup(&mut self, config: &Config);
+    /// Check whether the first bytes of an image look like something this processor can jump to.
+    /// `bank` is the bank the image is executed from, even if the preamble was read from an
+    /// update bank. The default implementation only rejects erased or zeroed memory.
+    fn is_bootable(
+        &self,
+        _config: &Config,
+        _bank: &Bank,
+        preamble: &[u8; IMAGE_PREAMBLE_SIZE],
+    ) -> bool {
+        is_programmed(preamble)
+    }
+}
+
+// Whether the preamble is neither erased nor zeroed
+fn is_programmed(preamble: &[u8; IMAGE_PREAMBLE_SIZE]) -> bool {
+    preamble.iter().any(|b| *b != 0xff) && preamble.iter().any(|b| *b != 0x00)
 }
 
 /// Implementation of a processor based on the cortex-m crate
 #[cfg(feature = "cortex-m")]
 pub mod cortex_m {
-    use super::Processor;
-    /// cortex-m based [Processor]
-    pub struct CortexM {}
+    use super::{Processor, IMAGE_PREAMBLE_SIZE};
+    use crate::{
+        hardware::{Bank, Config, LinkerConfig},
+        log, Address,
+    };
+
+    use cortex_m::peripheral::{ICB, MPU, NVIC, SCB, SYST};
+    #[cfg(feature = "defmt")]
+    use defmt::Format;
+    use heapless::Vec;
+
+    /// cortex-m based [Processor]. Before jumping, interrupts are disabled and cleared, SysTick is
+    /// stopped and the MPU is turned off unless memory protection is enabled, so the next image
+    /// starts with the peripherals of the core in their reset state.
+    pub struct CortexM {
+        reset_control: bool,
+        memory_protection: bool,
+        linker_config: Option<LinkerConfig>,
+    }
+
+    /// Errors that can occur while setting up memory protection
+    #[cfg_attr(feature = "use-defmt", derive(Format))]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum MpuError {
+        /// The MPU is not implemented or does not provide enough regions
+        Unavailable,
+        /// The size of the bank is not a power of two of at least 32 bytes
+        InvalidSize(Bank),
+        /// The location of the bank is not a multiple of its size
+        Misaligned(Bank),
+    }
+
+    // Maximum number of MPU regions used by moonboot
+    const MPU_REGIONS: usize = 3;
+
+    const MPU_CTRL_ENABLE: u32 = 1;
+    // Use the default memory map for privileged accesses outside of the configured regions
+    const MPU_CTRL_PRIVDEFENA: u32 = 1 << 2;
+    const MPU_RBAR_VALID: u32 = 1 << 4;
+    const MPU_RASR_ENABLE: u32 = 1;
+    const MPU_RASR_XN: u32 = 1 << 28;
+    const MPU_RASR_AP_RO: u32 = 0b110 << 24;
+    const MPU_RASR_AP_RW: u32 = 0b011 << 24;
+    // Normal memory, write-through cacheable
+    const MPU_RASR_FLASH: u32 = 1 << 17;
+    // Normal memory, non-cacheable, so the state is never stuck in a cache during a reset
+    #[cfg(any(feature = "ram-state", feature = "derive"))]
+    const MPU_RASR_RAM: u32 = 1 << 19;
+
+    // Register values of a single MPU region
+    struct MpuRegion {
+        rbar: u32,
+        rasr: u32,
+    }
+
+    impl MpuRegion {
+        fn new(bank: Bank, attributes: u32) -> Result<Self, MpuError> {
+            if bank.size < 32 || !bank.size.is_power_of_two() {
+                return Err(MpuError::InvalidSize(bank));
+            }
+            if bank.location & (bank.size - 1) != 0 {
+                return Err(MpuError::Misaligned(bank));
+            }
+
+            // SIZE encodes the region size as 2^(SIZE + 1)
+            let size = bank.size.trailing_zeros() - 1;
+            Ok(Self {
+                rbar: bank.location,
+                rasr: attributes | (size << 1) | MPU_RASR_ENABLE,
+            })
+        }
+    }
+
+    /// Check whether the banks of `config` can be protected by the MPU, which requires them to
+    /// have a size of a power of two and to be aligned to that size at their address, i.e. their
+    /// location plus `flash_origin`. Only the bootloader and update bank as well as the RAM state
+    /// are protected, all other memory is left untouched.
+    pub fn check_memory_protection(config: &Config, flash_origin: Address) -> Result<(), MpuError> {
+        mpu_regions(config, flash_origin).map(|_| ())
+    }
+
+    fn mpu_regions(
+        config: &Config,
+        flash_origin: Address,
+    ) -> Result<Vec<MpuRegion, MPU_REGIONS>, MpuError> {
+        let mut regions = Vec::new();
+        let mapped = |bank: Bank| Bank {
+            location: flash_origin + bank.location,
+            ..bank
+        };
+
+        // The bootloader must never be changed by the application, but stays executable as the
+        // bootloader itself runs from there
+        let _ = regions.push(MpuRegion::new(
+            mapped(config.bootloader_bank),
+            MPU_RASR_AP_RO | MPU_RASR_FLASH,
+        )?);
+
+        // The update bank is only data until the bootloader exchanged it
+        let _ = regions.push(MpuRegion::new(
+            mapped(config.update_bank),
+            MPU_RASR_AP_RW | MPU_RASR_XN | MPU_RASR_FLASH,
+        )?);
+
+        #[cfg(any(feature = "ram-state", feature = "derive"))]
+        {
+            // The state is usually not aligned, so protect the smallest region containing it
+            let (location, length) = crate::state::section::region();
+            let mut size = (length as Address).next_power_of_two().max(32);
+            while (location & !(size - 1)) + size < location + length as Address {
+                size *= 2;
+            }
+            let bank = Bank {
+                location: location & !(size - 1),
+                size,
+                memory_unit: crate::hardware::MemoryUnit::Internal,
+            };
+            let _ = regions.push(MpuRegion::new(
+                bank,
+                MPU_RASR_AP_RW | MPU_RASR_XN | MPU_RASR_RAM,
+            )?);
+        }
+
+        Ok(regions)
+    }
 
     impl CortexM {
         /// Instantiate a new processor
         pub fn new() -> Self {
-            Self {}
+            Self {
+                reset_control: true,
+                memory_protection: false,
+                linker_config: None,
+            }
+        }
+
+        /// Locate the banks of the [Config] at the origins of `linker_config` when jumping and
+        /// protecting memory, instead of assuming the flash to be mapped at address zero. This
+        /// also enables checking the vector table of an image before jumping to it: its initial
+        /// stack pointer has to point into the RAM and its reset vector into the boot bank.
+        /// Without it, only erased or zeroed images are rejected.
+        pub fn with_linker_config(mut self, linker_config: LinkerConfig) -> Self {
+            self.linker_config = Some(linker_config);
+            self
+        }
+
+        /// Whether to use the MPU to make the bootloader bank read-only and the update bank as well
+        /// as the RAM state non-executable. The MPU is configured in [Processor::setup] and stays
+        /// enabled for the next image. Only supported on cores implementing the ARMv6-M/ARMv7-M
+        /// MPU, see [check_memory_protection] for the requirements on the partitioning. Unless the
+        /// flash is mapped at address zero, set its origin with [CortexM::with_linker_config].
+        pub fn with_memory_protection(mut self, memory_protection: bool) -> Self {
+            self.memory_protection = memory_protection;
+            self
+        }
+
+        /// Whether to switch back to privileged mode on the main stack pointer before jumping,
+        /// which is the state the core is in after a reset. Enabled by default, disable this if
+        /// your bootloader runs unprivileged and cannot write the CONTROL register.
+        pub fn with_control_reset(mut self, reset_control: bool) -> Self {
+            self.reset_control = reset_control;
+            self
+        }
+
+        // Address the flash is mapped to
+        fn flash_origin(&self) -> Address {
+            self.linker_config
+                .map_or(0, |linker_config| linker_config.flash_origin)
+        }
+
+        // Disable and unpend all interrupts and stop SysTick
+        unsafe fn reset_interrupts(&mut self) {
+            let nvic = &*NVIC::PTR;
+            // The lowest bits of ICTR hold the number of implemented interrupt registers minus
+            // one. ARMv6-M does not implement ICTR and reads zero, which is correct there.
+            let registers = core::ptr::read_volatile(ICB::PTR as *const u32) & 0xf;
+            for register in 0..=(registers as usize).min(nvic.icer.len() - 1) {
+                nvic.icer[register].write(0xffff_ffff);
+                nvic.icpr[register].write(0xffff_ffff);
+            }
+
+            (*SYST::PTR).csr.write(0);
+            SCB::clear_pendst();
+            SCB::clear_pendsv();
+        }
+
+        // Program and enable the MPU with the given regions
+        unsafe fn enable_mpu(&mut self, regions: &[MpuRegion]) -> Result<(), MpuError> {
+            let mpu = &*MPU::PTR;
+
+            let available = ((mpu._type.read() >> 8) & 0xff) as usize;
+            if available < regions.len() {
+                return Err(MpuError::Unavailable);
+            }
+
+            mpu.ctrl.write(0);
+            for (index, region) in regions.iter().enumerate() {
+                mpu.rbar.write(region.rbar | MPU_RBAR_VALID | index as u32);
+                mpu.rasr.write(region.rasr);
+            }
+            mpu.ctrl.write(MPU_CTRL_ENABLE | MPU_CTRL_PRIVDEFENA);
+
+            cortex_m::asm::dsb();
+            cortex_m::asm::isb();
+
+            Ok(())
+        }
+
+        // Clean and disable the caches so the next image does not work on stale cache lines
+        #[cfg(feature = "cortex-m7")]
+        unsafe fn reset_caches(&mut self) {
+            let mut peripherals = cortex_m::Peripherals::steal();
+            peripherals.SCB.disable_icache();
+            peripherals.SCB.disable_dcache(&mut peripherals.CPUID);
+        }
+    }
+
+    impl Default for CortexM {
+        fn default() -> Self {
+            Self::new()
         }
     }
 
     impl Processor for CortexM {
         fn do_jump(&mut self, address: super::Address) -> ! {
+            let address = self.flash_origin() + address;
             unsafe {
+                cortex_m::interrupt::disable();
+
+                self.reset_interrupts();
+
+                if !self.memory_protection {
+                    (*MPU::PTR).ctrl.write(0);
+                }
+
+                #[cfg(feature = "cortex-m7")]
+                self.reset_caches();
+
+                cortex_m::asm::dsb();
+                cortex_m::asm::isb();
+
                 // Set Vector Table to new vector table (unsafe but okay here)
-                (*cortex_m::peripheral::SCB::ptr()).vtor.write(address);
+                (*SCB::ptr()).vtor.write(address);
 
-                cortex_m::asm::bootload(address as *const u32);
+                // Every interrupt source is disabled now, so restore the reset value of PRIMASK
+                // for the next image.
+                cortex_m::interrupt::enable();
+
+                let control = if self.reset_control {
+                    0
+                } else {
+                    cortex_m::register::control::read().bits()
+                };
+
+                jump(control, address as *const u32);
             }
         }
 
-        fn setup(&mut self, config: &crate::hardware::Config) {
-            // Nothing to do!
+        fn setup(&mut self, config: &Config) {
+            if !self.memory_protection {
+                return;
+            }
+
+            let result = mpu_regions(config, self.flash_origin())
+                .and_then(|regions| unsafe { self.enable_mpu(&regions) });
+            if let Err(err) = result {
+                log::error!("Could not enable memory protection: {:?}", err);
+            }
         }
+
+        fn is_bootable(
+            &self,
+            config: &Config,
+            bank: &Bank,
+            preamble: &[u8; IMAGE_PREAMBLE_SIZE],
+        ) -> bool {
+            let linker_config = match self.linker_config {
+                Some(linker_config) => linker_config,
+                None => return super::is_programmed(preamble),
+            };
+
+            // The vector table starts with the initial stack pointer followed by the reset vector
+            let stack_pointer =
+                u32::from_le_bytes([preamble[0], preamble[1], preamble[2], preamble[3]]);
+            let reset_vector =
+                u32::from_le_bytes([preamble[4], preamble[5], preamble[6], preamble[7]]);
+
+            let ram_start = linker_config.ram_origin as u64 + config.ram_bank.location as u64;
+            let stack_pointer = stack_pointer as u64;
+            let stack_pointer_valid = stack_pointer & 0b11 == 0
+                && stack_pointer > ram_start
+                && stack_pointer <= ram_start + config.ram_bank.size as u64;
+
+            // Thumb bit has to be set, and the handler has to be part of the image
+            let bank_start = linker_config.flash_origin as u64 + bank.location as u64;
+            let reset_address = (reset_vector & !1) as u64;
+            let reset_vector_valid = reset_vector & 1 == 1
+                && reset_address >= bank_start
+                && reset_address < bank_start + bank.size as u64;
+
+            stack_pointer_valid && reset_vector_valid
+        }
+    }
+
+    // Write CONTROL and jump to the vector table in one go, as switching the stack pointer would
+    // break any code relying on the current stack afterwards.
+    #[cfg(target_arch = "arm")]
+    unsafe fn jump(control: u32, vector_table: *const u32) -> ! {
+        let stack_pointer = core::ptr::read_volatile(vector_table);
+        let reset_vector = core::ptr::read_volatile(vector_table.offset(1));
+        core::arch::asm!(
+            "msr CONTROL, {control}",
+            "isb",
+            "msr MSP, {stack_pointer}",
+            "bx {reset_vector}",
+            control = in(reg) control,
+            stack_pointer = in(reg) stack_pointer,
+            reset_vector = in(reg) reset_vector,
+            options(noreturn, nomem, nostack),
+        );
+    }
+
+    #[cfg(not(target_arch = "arm"))]
+    unsafe fn jump(_control: u32, vector_table: *const u32) -> ! {
+        cortex_m::asm::bootload(vector_table)
     }
 }
 
+/// Implementation of a processor based on the riscv crate, only available when building for a
+/// RISC-V target
+#[cfg(all(
+    feature = "riscv",
+    any(target_arch = "riscv32", target_arch = "riscv64")
+))]
+pub mod riscv {
+    use super::Processor;
+    use crate::{
+        hardware::{Config, LinkerConfig},
+        Address,
+    };
+
+    use riscv::register::{mie, mstatus, mtvec};
+
+    /// riscv based [Processor], running in machine mode. Before jumping, interrupts are disabled,
+    /// `mtvec` points to the next image and the instruction cache is synchronized with the memory.
+    pub struct Riscv {
+        linker_config: Option<LinkerConfig>,
+    }
+
+    impl Riscv {
+        /// Instantiate a new processor
+        pub fn new() -> Self {
+            Self {
+                linker_config: None,
+            }
+        }
+
+        /// Locate the banks of the [Config] at the flash origin of `linker_config` when jumping,
+        /// instead of assuming the flash to be mapped at address zero
+        pub fn with_linker_config(mut self, linker_config: LinkerConfig) -> Self {
+            self.linker_config = Some(linker_config);
+            self
+        }
+
+        // Address the flash is mapped to
+        fn flash_origin(&self) -> Address {
+            self.linker_config
+                .map_or(0, |linker_config| linker_config.flash_origin)
+        }
+    }
+
+    impl Default for Riscv {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Processor for Riscv {
+        fn do_jump(&mut self, address: super::Address) -> ! {
+            let address = self.flash_origin() + address;
+            unsafe {
+                mstatus::clear_mie();
+                mie::clear_mext();
+                mie::clear_mtimer();
+                mie::clear_msoft();
+
+                // Traps occuring before the next image installed its own handlers end up in the
+                // next image instead of the bootloader
+                mtvec::write(address as usize, mtvec::TrapMode::Direct);
+
+                jump(address as usize);
+            }
+        }
+
+        fn setup(&mut self, _config: &Config) {
+            // Nothing to do!
+        }
+    }
+
+    // Synchronize the instruction fetches with the (possibly just exchanged) memory and jump
+    unsafe fn jump(address: usize) -> ! {
+        core::arch::asm!(
+            "fence.i",
+            "jr {address}",
+            address = in(reg) address,
+            options(noreturn),
+        );
+    }
+}
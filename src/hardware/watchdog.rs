@@ -0,0 +1,112 @@
+use crate::{hardware::Config, state::ImageSet, Address};
+
+use embedded_storage::{ReadStorage, Storage};
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+/// Watchdog fed by the bootloader between flash operations, so it does not reset the device in
+/// the middle of a long exchange. Use [Config::estimate_exchange] to check the watchdog timeout is
+/// long enough.
+pub trait Watchdog {
+    /// Restart the watchdog timeout
+    fn feed(&mut self);
+}
+
+/// Placeholder for devices without a running watchdog
+pub struct NoWatchdog;
+
+impl Watchdog for NoWatchdog {
+    fn feed(&mut self) {}
+}
+
+// Storage feeding the watchdog before every access, for operations reading a whole bank chunk by
+// chunk, like hashing an image or scanning the history
+pub(crate) struct Feeding<'a, S, W> {
+    pub(crate) storage: &'a mut S,
+    pub(crate) watchdog: &'a mut W,
+}
+
+impl<S: ReadStorage, W: Watchdog> ReadStorage for Feeding<'_, S, W> {
+    type Error = S::Error;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.watchdog.feed();
+        self.storage.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.storage.capacity()
+    }
+}
+
+impl<S: Storage, W: Watchdog> Storage for Feeding<'_, S, W> {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.watchdog.feed();
+        self.storage.write(offset, bytes)
+    }
+}
+
+/// Worst case duration of the flash operations on a single page in microseconds, as found in the
+/// datasheet of the flash
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct FlashTiming {
+    /// Reading a page
+    pub read_us: u32,
+    /// Erasing a page
+    pub erase_us: u32,
+    /// Programming an erased page
+    pub write_us: u32,
+}
+
+/// Estimated duration of an exchange, see [Config::estimate_exchange]
+#[cfg_attr(feature = "defmt", derive(Format))]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct ExchangeEstimate {
+    /// Number of pages exchanged
+    pub pages: u32,
+    /// Duration of the whole exchange in microseconds
+    pub total_us: u64,
+    /// Longest time the watchdog is not fed during the exchange in microseconds
+    pub feed_interval_us: u32,
+}
+
+impl ExchangeEstimate {
+    /// Whether a watchdog with the given timeout is fed often enough during the exchange
+    pub fn fits_watchdog(&self, timeout_us: u32) -> bool {
+        self.feed_interval_us < timeout_us
+    }
+}
+
+impl Config {
+    /// Estimate the worst case duration of exchanging the given images with pages of `page_size`
+    /// bytes, assuming their whole banks are exchanged and no page is identical in both banks.
+    /// Every page of both banks is read, erased and written once, feeding the watchdog before each
+    /// of these operations. Verifying an image and scanning the history feed the watchdog before
+    /// every page read as well, so `read_us` should include hashing a page. Compare the result
+    /// against the watchdog timeout in a test to catch a misconfiguration before it resets a
+    /// device in the middle of an update.
+    pub fn estimate_exchange(
+        &self,
+        images: ImageSet,
+        page_size: usize,
+        timing: FlashTiming,
+    ) -> ExchangeEstimate {
+        let pages = images
+            .iter()
+            .filter_map(|image| self.image_banks(image))
+            .map(|banks| banks.boot_bank.size.div_ceil(page_size as Address))
+            .sum::<u32>();
+        let page_us = 2 * (timing.read_us as u64 + timing.erase_us as u64 + timing.write_us as u64);
+
+        ExchangeEstimate {
+            pages,
+            total_us: pages as u64 * page_us,
+            feed_interval_us: core::cmp::max(
+                timing.read_us,
+                timing.erase_us.saturating_add(timing.write_us),
+            ),
+        }
+    }
+}
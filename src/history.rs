@@ -0,0 +1,207 @@
+use crate::{
+    hardware::Bank,
+    state::{ImageSet, UpdateError},
+    Address,
+};
+
+use crc::{Crc, CRC_16_IBM_SDLC};
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use embedded_storage::{ReadStorage, Storage};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Size of a single serialized [Record] in the history bank
+pub const RECORD_SIZE: usize = 16;
+
+const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+
+/// Event of the update process recorded in the history
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The application requested an update of the given images
+    UpdateRequested(ImageSet),
+    /// The application withdrew its update request before it was installed
+    UpdateCancelled(ImageSet),
+    /// The bootloader started to exchange the given images with their update banks
+    ExchangeStarted(ImageSet),
+    /// The bootloader finished installing an update of the given images
+    ExchangeCompleted(ImageSet),
+    /// The bootloader found an exchange interrupted at the given image and page, e.g. by a
+    /// power loss, and continues it
+    ExchangeInterrupted {
+        /// Index of the image which was being exchanged
+        image: u8,
+        /// Page of the image which was being exchanged
+        page_index: u32,
+    },
+    /// The bootloader reverted the given images to their previous version
+    RevertExecuted(ImageSet),
+    /// The application marked the given images as successfully booted
+    BootConfirmed(ImageSet),
+    /// The application requested a rollback of the given images to their previous version
+    RollbackRequested(ImageSet),
+    /// The update failed with the given error
+    Error(UpdateError),
+}
+
+/// Entry of the update history
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    /// Number of this record, incremented for every record ever written
+    pub sequence: u32,
+    /// Number of the boot this record was written in. The bootloader increments it with the first
+    /// record of every boot, so boots which did not record anything are not counted.
+    pub boot: u32,
+    /// What happened
+    pub event: Event,
+}
+
+impl Record {
+    /// Serialize the record including its checksum
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0_u8; RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.boot.to_le_bytes());
+        let (kind, payload) = match self.event {
+            Event::UpdateRequested(images) => (1, [images.bits(), 0, 0, 0, 0]),
+            Event::ExchangeStarted(images) => (2, [images.bits(), 0, 0, 0, 0]),
+            Event::ExchangeCompleted(images) => (3, [images.bits(), 0, 0, 0, 0]),
+            Event::ExchangeInterrupted { image, page_index } => {
+                let page = page_index.to_le_bytes();
+                (4, [image, page[0], page[1], page[2], page[3]])
+            }
+            Event::RevertExecuted(images) => (5, [images.bits(), 0, 0, 0, 0]),
+            Event::BootConfirmed(images) => (6, [images.bits(), 0, 0, 0, 0]),
+            Event::Error(err) => (7, [error_code(err), 0, 0, 0, 0]),
+            Event::UpdateCancelled(images) => (8, [images.bits(), 0, 0, 0, 0]),
+            Event::RollbackRequested(images) => (9, [images.bits(), 0, 0, 0, 0]),
+        };
+        bytes[8] = kind;
+        bytes[9..14].copy_from_slice(&payload);
+        let crc = CRC.checksum(&bytes[0..14]);
+        bytes[14..16].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Deserialize a record, returning None for erased or corrupted records
+    pub fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Option<Self> {
+        if u16::from_le_bytes([bytes[14], bytes[15]]) != CRC.checksum(&bytes[0..14]) {
+            return None;
+        }
+
+        let images = ImageSet::from_bits(bytes[9]);
+        let event = match bytes[8] {
+            1 => Event::UpdateRequested(images),
+            2 => Event::ExchangeStarted(images),
+            3 => Event::ExchangeCompleted(images),
+            4 => Event::ExchangeInterrupted {
+                image: bytes[9],
+                page_index: u32::from_le_bytes([bytes[10], bytes[11], bytes[12], bytes[13]]),
+            },
+            5 => Event::RevertExecuted(images),
+            6 => Event::BootConfirmed(images),
+            7 => Event::Error(error_from_code(bytes[9])?),
+            8 => Event::UpdateCancelled(images),
+            9 => Event::RollbackRequested(images),
+            _ => return None,
+        };
+
+        Some(Self {
+            sequence: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            boot: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            event,
+        })
+    }
+}
+
+fn error_code(err: UpdateError) -> u8 {
+    match err {
+        UpdateError::InvalidImageIndex => 0,
+        UpdateError::ImageExchangeFailed => 1,
+        UpdateError::InvalidState => 2,
+        UpdateError::InvalidSignature => 3,
+        UpdateError::DependencyMismatch => 4,
+        UpdateError::NoPreviousImage => 5,
+    }
+}
+
+fn error_from_code(code: u8) -> Option<UpdateError> {
+    match code {
+        0 => Some(UpdateError::InvalidImageIndex),
+        1 => Some(UpdateError::ImageExchangeFailed),
+        2 => Some(UpdateError::InvalidState),
+        3 => Some(UpdateError::InvalidSignature),
+        4 => Some(UpdateError::DependencyMismatch),
+        5 => Some(UpdateError::NoPreviousImage),
+        _ => None,
+    }
+}
+
+// Number of records fitting into the bank
+fn capacity(bank: Bank) -> u32 {
+    bank.size / RECORD_SIZE as Address
+}
+
+fn read_slot<S: ReadStorage>(storage: &mut S, bank: Bank, slot: u32) -> Option<Record> {
+    let mut bytes = [0_u8; RECORD_SIZE];
+    storage
+        .read(bank.location + slot * RECORD_SIZE as Address, &mut bytes)
+        .ok()?;
+    Record::from_bytes(&bytes)
+}
+
+/// Find the most recent record in the history stored in `bank`
+pub fn newest<S: ReadStorage>(storage: &mut S, bank: Bank) -> Option<Record> {
+    (0..capacity(bank))
+        .filter_map(|slot| read_slot(storage, bank, slot))
+        .max_by_key(|record| record.sequence)
+}
+
+/// Read the record written `index` records before the most recent one, so index 0 is the most
+/// recent record. Returns None if there is no such record, e.g. because it was already
+/// overwritten.
+pub fn read<S: ReadStorage>(storage: &mut S, bank: Bank, index: u32) -> Option<Record> {
+    let newest = newest(storage, bank)?;
+    let sequence = newest.sequence.checked_sub(index)?;
+    read_slot(storage, bank, sequence % capacity(bank)).filter(|record| record.sequence == sequence)
+}
+
+/// Append an event to the history stored in `bank`, overwriting the oldest record once the bank is
+/// full. `new_boot` increments the boot number of the record.
+pub fn append<S: Storage>(
+    storage: &mut S,
+    bank: Bank,
+    event: Event,
+    new_boot: bool,
+) -> Result<Record, ()> {
+    if capacity(bank) == 0 {
+        return Err(());
+    }
+
+    let record = match newest(storage, bank) {
+        Some(newest) => Record {
+            sequence: newest.sequence.wrapping_add(1),
+            boot: newest.boot.wrapping_add(new_boot as u32),
+            event,
+        },
+        None => Record {
+            sequence: 0,
+            boot: 0,
+            event,
+        },
+    };
+
+    let slot = record.sequence % capacity(bank);
+    storage
+        .write(
+            bank.location + slot * RECORD_SIZE as Address,
+            &record.to_bytes(),
+        )
+        .map_err(|_| ())?;
+    Ok(record)
+}
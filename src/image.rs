@@ -0,0 +1,218 @@
+use crate::{hardware::Bank, Address};
+
+use crc::{Crc, CRC_32_CKSUM};
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use embedded_storage::ReadStorage;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Magic number at the start of every serialized [ImageHeader] ("MOON")
+pub const IMAGE_HEADER_MAGIC: u32 = 0x4e4f_4f4d;
+/// Size of a serialized [ImageHeader] in bytes
+pub const IMAGE_HEADER_SIZE: usize = 64;
+/// Size of the SHA-256 hash stored in the [ImageHeader]
+pub const IMAGE_HASH_SIZE: usize = 32;
+
+// Image index marking a header without dependency
+const NO_DEPENDENCY: u8 = 0xff;
+
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+
+/// Version of a firmware image
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ImageVersion {
+    /// Major version, incremented on incompatible changes
+    pub major: u8,
+    /// Minor version, incremented on compatible changes
+    pub minor: u8,
+    /// Patch version, incremented on bug fixes
+    pub patch: u16,
+}
+
+/// Requirement of an image on the version of another image, e.g. a network core firmware which
+/// only works with an application using a recent enough protocol version
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageDependency {
+    /// Index of the image this image depends on, see `Config::image_banks`
+    pub image: u8,
+    /// Minimum version the other image needs to have
+    pub min_version: ImageVersion,
+}
+
+/// Metadata stored alongside an image in its bank, used to decide whether the image can be booted.
+/// The header is located at `Config::image_header_offset` relative to the start of the bank and is
+/// excluded from the hash.
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// Version of this image
+    pub version: ImageVersion,
+    /// Size of the image in bytes, starting at the beginning of the bank and including the header
+    pub size: u32,
+    /// Another image which has to be installed in a specific version for this image to work
+    pub dependency: Option<ImageDependency>,
+    /// SHA-256 over the first `size` bytes of the bank, skipping the header itself
+    pub hash: [u8; IMAGE_HASH_SIZE],
+}
+
+/// Errors that can occur while validating an image
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    /// The underlying storage could not be read
+    ReadFailure,
+    /// No valid header could be found at the configured offset
+    InvalidHeader,
+    /// The size in the header does not fit into the bank
+    InvalidSize,
+    /// The hash of the image does not match the one in its header
+    HashMismatch,
+}
+
+impl ImageHeader {
+    /// Serialize the header including magic number and checksum, e.g. to write it from a host tool
+    pub fn to_bytes(&self) -> [u8; IMAGE_HEADER_SIZE] {
+        let mut bytes = [0_u8; IMAGE_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&IMAGE_HEADER_MAGIC.to_le_bytes());
+        bytes[4] = self.version.major;
+        bytes[5] = self.version.minor;
+        bytes[6..8].copy_from_slice(&self.version.patch.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.size.to_le_bytes());
+        match self.dependency {
+            Some(dependency) => {
+                bytes[12] = dependency.image;
+                bytes[16] = dependency.min_version.major;
+                bytes[17] = dependency.min_version.minor;
+                bytes[18..20].copy_from_slice(&dependency.min_version.patch.to_le_bytes());
+            }
+            None => bytes[12] = NO_DEPENDENCY,
+        }
+        bytes[20..52].copy_from_slice(&self.hash);
+        // 52..60 is reserved for future use
+        let crc = CRC.checksum(&bytes[0..60]);
+        bytes[60..64].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Deserialize a header, returning None if the magic number or checksum do not match
+    pub fn from_bytes(bytes: &[u8; IMAGE_HEADER_SIZE]) -> Option<Self> {
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+
+        if word(0) != IMAGE_HEADER_MAGIC || word(60) != CRC.checksum(&bytes[0..60]) {
+            return None;
+        }
+
+        let dependency = match bytes[12] {
+            NO_DEPENDENCY => None,
+            image => Some(ImageDependency {
+                image,
+                min_version: ImageVersion {
+                    major: bytes[16],
+                    minor: bytes[17],
+                    patch: u16::from_le_bytes([bytes[18], bytes[19]]),
+                },
+            }),
+        };
+
+        let mut hash = [0_u8; IMAGE_HASH_SIZE];
+        hash.copy_from_slice(&bytes[20..52]);
+
+        Some(Self {
+            version: ImageVersion {
+                major: bytes[4],
+                minor: bytes[5],
+                patch: u16::from_le_bytes([bytes[6], bytes[7]]),
+            },
+            size: word(8),
+            dependency,
+            hash,
+        })
+    }
+}
+
+/// Read the header of the image stored in `bank`
+pub fn read_header<S: ReadStorage>(
+    storage: &mut S,
+    bank: Bank,
+    header_offset: Address,
+) -> Result<ImageHeader, ImageError> {
+    if header_offset + IMAGE_HEADER_SIZE as Address > bank.size {
+        return Err(ImageError::InvalidHeader);
+    }
+
+    let mut bytes = [0_u8; IMAGE_HEADER_SIZE];
+    storage
+        .read(bank.location + header_offset, &mut bytes)
+        .map_err(|_| ImageError::ReadFailure)?;
+
+    ImageHeader::from_bytes(&bytes).ok_or(ImageError::InvalidHeader)
+}
+
+/// Check the image in `bank` against the size and hash stored in `header`. `buf` is used as
+/// scratch memory while reading the image, larger buffers result in fewer read operations.
+pub fn verify<S: ReadStorage>(
+    storage: &mut S,
+    bank: Bank,
+    header_offset: Address,
+    header: &ImageHeader,
+    buf: &mut [u8],
+) -> Result<(), ImageError> {
+    if buf.is_empty() {
+        // Nothing could ever be read into this buffer
+        return Err(ImageError::ReadFailure);
+    }
+
+    let header_end = header_offset + IMAGE_HEADER_SIZE as Address;
+    if header.size > bank.size || header.size < header_end {
+        return Err(ImageError::InvalidSize);
+    }
+
+    let mut hasher = Sha256::new();
+    hash_range(storage, &mut hasher, bank.location, header_offset, buf)?;
+    hash_range(
+        storage,
+        &mut hasher,
+        bank.location + header_end,
+        header.size - header_end,
+        buf,
+    )?;
+
+    if hasher.finalize().as_slice() == header.hash {
+        Ok(())
+    } else {
+        Err(ImageError::HashMismatch)
+    }
+}
+
+fn hash_range<S: ReadStorage>(
+    storage: &mut S,
+    hasher: &mut Sha256,
+    start: Address,
+    length: Address,
+    buf: &mut [u8],
+) -> Result<(), ImageError> {
+    let mut offset = 0;
+    while offset < length {
+        let chunk = core::cmp::min(buf.len() as Address, length - offset) as usize;
+        storage
+            .read(start + offset, &mut buf[..chunk])
+            .map_err(|_| ImageError::ReadFailure)?;
+        hasher.update(&buf[..chunk]);
+        offset += chunk as Address;
+    }
+    Ok(())
+}
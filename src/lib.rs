@@ -1,4 +1,5 @@
 #![no_std]
+#![allow(clippy::result_unit_err)]
 
 //!Moonboot is a framework to build bootloaders for embedded devices, or other kinds of no_std
 //!Rust environments.
@@ -7,19 +8,26 @@
 //!* Partitioning of your memory into different sections
 //!* Exchange of the contents of those partitions via the bootloader
 //!* Signature/Checksum-checking of the partitions contents with an algorithm of your choice, because it is
-//!done in firmware, not in bootloader
+//!  done in firmware, not in bootloader
 //!* Automatic Linker Script generation based on a Section/Parition Description in Rust Code
 
 mod boot;
 /// Implementations for use in the bootloader
-pub use boot::MoonbootBoot;
+pub use boot::{BootAction, BootContext, BootPolicy, DefaultPolicy, MoonbootBoot, RetryPolicy};
 
 mod manager;
+/// Implementations for use in firmware accessing the flash asynchronously
+#[cfg(feature = "async")]
+pub use manager::{AsyncMoonbootManager, AsyncUpdateWriter};
 /// Implementations for use in the firmware
-pub use manager::MoonbootManager;
+pub use manager::{BankReader, BootStatus, MoonbootManager, UpdateWriter, WriteError};
 
 /// Common hardware abstractions and associated implementations
 pub mod hardware;
+/// Ring buffer of update events persisted in a flash bank, used to diagnose updates in the field
+pub mod history;
+/// Image header format and validity checks of images stored in banks
+pub mod image;
 /// Shared state management between firmware and bootloader
 pub mod state;
 
@@ -37,6 +45,54 @@ pub type Address = u32;
 /// uninitialize your hardware.
 pub use moonboot_macros::pre_jump_handler;
 
+/// Marker macro for a handler fn `fn()` invoked by the bootloader before it starts to install an
+/// update
+pub use moonboot_macros::on_update_start;
+
+/// Marker macro for a handler fn `fn(page: u32, total: u32)` invoked by the bootloader after
+/// every page exchanged, with `page` pages out of `total` of the current image done. Use this to
+/// drive a progress indicator or feed a watchdog.
+pub use moonboot_macros::on_exchange_progress;
+
+/// Marker macro for a handler fn `fn()` invoked by the bootloader once an update is installed
+pub use moonboot_macros::on_update_complete;
+
+/// Marker macro for a handler fn `fn()` invoked by the bootloader once it reverted images to their
+/// previous version
+pub use moonboot_macros::on_revert;
+
+/// Marker macro for a handler fn `fn(error: moonboot::state::UpdateError)` invoked by the
+/// bootloader if an update fails
+pub use moonboot_macros::on_error;
+
+/// Lay out the partitions of a device at compile time, defining the constants `MOONBOOT_CONFIG`,
+/// `MOONBOOT_LINKER_CONFIG` and `MOONBOOT_PAGE_SIZE`. Partitions are placed one after another
+/// starting at the flash origin, in the order bootloader, boot, update, additional images and
+/// history. Sizes are given in KiB, addresses and the erase size in bytes. Misaligned partitions
+/// or partitions exceeding the flash are reported as compile errors, and the generated config is
+/// checked with [hardware::Config::validate] at compile time. Like every [hardware::Bank],
+/// the partitions in `MOONBOOT_CONFIG` are located relative to the origins in
+/// `MOONBOOT_LINKER_CONFIG`.
+///
+/// ```ignore
+/// moonboot::partitions! {
+///     flash: { origin: 0x0800_0000, size: 512, erase_size: 0x4000 },
+///     ram: { origin: 0x2000_0000, size: 128, state: true },
+///     bootloader: 64,
+///     boot: 192,
+///     update: 192,
+///     // optional, up to three additional images
+///     images: [{ boot: 16, update: 16 }],
+///     // optional
+///     history: 16,
+///     image_header_offset: 0x400,
+/// }
+/// ```
+///
+/// The constants can be used in the firmware directly, or passed to the linker script generators
+/// of `moonboot-codegen` in a build.rs.
+pub use moonboot_macros::partitions;
+
 #[cfg(feature = "use-defmt")]
 pub(crate) use defmt as log;
 
@@ -46,22 +102,45 @@ pub(crate) use logger_crate as log;
 #[cfg(not(any(feature = "use-log", feature = "use-defmt")))]
 pub(crate) mod log {
     macro_rules! info {
-        ( $( $x:expr ),* ) => {};
+        ( $( $x:expr ),* ) => {{
+            $( let _ = &$x; )*
+        }};
     }
     pub(crate) use info;
     macro_rules! trace {
-        ( $( $x:expr ),* ) => {};
+        ( $( $x:expr ),* ) => {{
+            $( let _ = &$x; )*
+        }};
     }
     pub(crate) use trace;
     macro_rules! error {
-        ( $( $x:expr ),* ) => {};
+        ( $( $x:expr ),* ) => {{
+            $( let _ = &$x; )*
+        }};
     }
     pub(crate) use error;
     macro_rules! warner {
-        ( $( $x:expr ),* ) => {};
+        ( $( $x:expr ),* ) => {{
+            $( let _ = &$x; )*
+        }};
     }
     pub(crate) use warner as warn;
 }
 
 #[export_name = "__moonboots_default_pre_jump"]
 fn default_pre_jump() {}
+
+#[export_name = "__moonboots_default_on_update_start"]
+fn default_on_update_start() {}
+
+#[export_name = "__moonboots_default_on_exchange_progress"]
+fn default_on_exchange_progress(_page: u32, _total: u32) {}
+
+#[export_name = "__moonboots_default_on_update_complete"]
+fn default_on_update_complete() {}
+
+#[export_name = "__moonboots_default_on_revert"]
+fn default_on_revert() {}
+
+#[export_name = "__moonboots_default_on_error"]
+fn default_on_error(_error: state::UpdateError) {}
@@ -0,0 +1,312 @@
+use crate::{
+    hardware::{processor::Processor, Bank, Config, ConfigError, MAX_IMAGES},
+    image::{ImageHeader, ImageVersion, IMAGE_HEADER_SIZE},
+    state::{ImageSet, MoonbootState, State},
+    Address,
+};
+
+use super::{
+    transitions::{self, Outcome},
+    writer::{self, ImageStream, Operation},
+    BootStatus, WriteError,
+};
+
+use embedded_storage_async::nor_flash::NorFlash;
+
+use crate::log;
+
+/// Variant of `MoonbootManager` for flash with an async interface, e.g. on embassy, so writing
+/// the update bank does not block the executor. The bootloader keeps using the blocking API.
+///
+/// Update events are not recorded in the [crate::history] and there is no `read_history`, as the
+/// history requires the blocking `Storage` interface. Requested and cancelled updates are missing
+/// from the history, the bootloader still records the events of the exchange.
+pub struct AsyncMoonbootManager<
+    InternalMemory: NorFlash,
+    HardwareState: State,
+    CPU: Processor,
+    const INTERNAL_PAGE_SIZE: usize,
+> {
+    config: Config,
+    internal_memory: InternalMemory,
+    state: HardwareState,
+    processor: CPU,
+    // Images whose update bank is being written by an AsyncUpdateWriter which has not finished yet
+    unverified: ImageSet,
+}
+
+impl<
+        InternalMemory: NorFlash,
+        HardwareState: State,
+        CPU: Processor,
+        const INTERNAL_PAGE_SIZE: usize,
+    > AsyncMoonbootManager<InternalMemory, HardwareState, CPU, INTERNAL_PAGE_SIZE>
+{
+    pub fn new(
+        config: Config,
+        internal_memory: InternalMemory,
+        state: HardwareState,
+        processor: CPU,
+    ) -> Result<Self, ConfigError> {
+        config.validate(INTERNAL_PAGE_SIZE)?;
+        config.validate_capacity(internal_memory.capacity())?;
+
+        Ok(Self {
+            config,
+            internal_memory,
+            state,
+            processor,
+            unverified: ImageSet::empty(),
+        })
+    }
+
+    /// Destroy this instance of the boot manager and return access to the hardware peripheral
+    pub fn destroy(self) -> (InternalMemory, HardwareState, CPU) {
+        (self.internal_memory, self.state, self.processor)
+    }
+
+    /// See `MoonbootManager::mark_boot_successful`
+    pub fn mark_boot_successful(&mut self) -> Result<(), ()> {
+        self.transition(transitions::mark_boot_successful)
+    }
+
+    /// See `MoonbootManager::clear_error`
+    pub fn clear_error(&mut self) -> Result<(), ()> {
+        self.transition(transitions::clear_error)
+    }
+
+    /// See `MoonbootManager::mark_image_successful`
+    pub fn mark_image_successful(&mut self, image: u8) -> Result<(), ()> {
+        self.transition(|state| transitions::mark_image_successful(state, image))
+    }
+
+    /// See `MoonbootManager::update`
+    pub fn update(&mut self) -> Result<void::Void, ()> {
+        self.update_images(ImageSet::MAIN)
+    }
+
+    /// See `MoonbootManager::update_images`
+    pub fn update_images(&mut self, images: ImageSet) -> Result<void::Void, ()> {
+        self.schedule_images(images)?;
+
+        log::info!("Stored update request, jumping to bootloader! Geronimo!");
+
+        super::jump_to_bootloader(&self.config, &mut self.processor)
+    }
+
+    /// See `MoonbootManager::revert`
+    pub fn revert(&mut self) -> Result<void::Void, ()> {
+        self.revert_images(ImageSet::MAIN)
+    }
+
+    /// See `MoonbootManager::revert_images`
+    pub fn revert_images(&mut self, images: ImageSet) -> Result<void::Void, ()> {
+        self.schedule_revert(images)?;
+
+        log::info!("Stored rollback request, jumping to bootloader!");
+
+        super::jump_to_bootloader(&self.config, &mut self.processor)
+    }
+
+    /// See `MoonbootManager::schedule_revert`
+    pub fn schedule_revert(&mut self, images: ImageSet) -> Result<(), ()> {
+        self.transition(|state| transitions::schedule_revert(state, images))
+    }
+
+    /// See `MoonbootManager::schedule_update`
+    pub fn schedule_update(&mut self) -> Result<(), ()> {
+        self.schedule_images(ImageSet::MAIN)
+    }
+
+    /// See `MoonbootManager::schedule_images`
+    pub fn schedule_images(&mut self, images: ImageSet) -> Result<(), ()> {
+        let (config, unverified) = (self.config, self.unverified);
+        self.transition(|state| transitions::schedule_images(&config, unverified, state, images))
+    }
+
+    /// See `MoonbootManager::cancel_update`
+    pub fn cancel_update(&mut self) -> Result<(), ()> {
+        self.transition(transitions::cancel_update)
+    }
+
+    /// See `MoonbootManager::status`
+    pub async fn status(&mut self) -> BootStatus {
+        let mut status = transitions::status(&self.state.read());
+
+        if let Some(header_offset) = self.config.image_header_offset {
+            for image in 0..MAX_IMAGES {
+                if let Some(banks) = self.config.image_banks(image as u8) {
+                    status.boot_versions[image] =
+                        self.read_version(banks.boot_bank, header_offset).await;
+                    status.update_versions[image] =
+                        self.read_version(banks.update_bank, header_offset).await;
+                }
+            }
+        }
+
+        status
+    }
+
+    /// Read from the update bank of the given image
+    pub async fn read_update_bank(
+        &mut self,
+        image: u8,
+        offset: u32,
+        bytes: &mut [u8],
+    ) -> Result<(), ()> {
+        let bank = self.config.image_banks(image).ok_or(())?.update_bank;
+        if offset > bank.size || offset + bytes.len() as u32 > bank.size {
+            return Err(());
+        }
+
+        log::info!("Reading at {:x}[{:x}]", bank.location, offset);
+        self.internal_memory
+            .read(bank.location + offset, bytes)
+            .await
+            .map_err(|_| ())
+    }
+
+    /// See `MoonbootManager::update_writer`
+    pub async fn update_writer(
+        &mut self,
+        image: u8,
+    ) -> Result<AsyncUpdateWriter<'_, InternalMemory, HardwareState, INTERNAL_PAGE_SIZE>, WriteError>
+    {
+        self.start_writer(image, None).await
+    }
+
+    /// See `MoonbootManager::resume_update_writer`
+    pub async fn resume_update_writer(
+        &mut self,
+        image: u8,
+        id: u32,
+    ) -> Result<AsyncUpdateWriter<'_, InternalMemory, HardwareState, INTERNAL_PAGE_SIZE>, WriteError>
+    {
+        self.start_writer(image, Some(id)).await
+    }
+
+    async fn start_writer(
+        &mut self,
+        image: u8,
+        id: Option<u32>,
+    ) -> Result<AsyncUpdateWriter<'_, InternalMemory, HardwareState, INTERNAL_PAGE_SIZE>, WriteError>
+    {
+        let bank = self
+            .config
+            .image_banks(image)
+            .ok_or(WriteError::InvalidImage)?
+            .update_bank;
+
+        let resume_offset = writer::prepare_cursor(&mut self.state, image, id)?;
+
+        let stream = ImageStream::new(
+            image,
+            bank,
+            self.config.image_header_offset,
+            InternalMemory::WRITE_SIZE,
+            InternalMemory::ERASE_SIZE,
+        )?;
+
+        // The previous contents of the update bank are about to be destroyed
+        self.unverified = self.unverified.with(image);
+
+        let mut writer = AsyncUpdateWriter {
+            flash: &mut self.internal_memory,
+            unverified: &mut self.unverified,
+            cursor: id.map(|id| (&mut self.state, id)),
+            stream,
+        };
+        if resume_offset != 0 {
+            writer.resume(resume_offset).await?;
+        }
+
+        Ok(writer)
+    }
+
+    // Apply a transition to the shared state, writing it if it changed
+    fn transition(
+        &mut self,
+        transition: impl FnOnce(&mut MoonbootState) -> Result<Outcome, ()>,
+    ) -> Result<(), ()> {
+        let mut current_state = self.state.read();
+        if let Outcome::Changed(_) = transition(&mut current_state)? {
+            self.state.write(current_state)?;
+        }
+        Ok(())
+    }
+
+    async fn read_version(&mut self, bank: Bank, header_offset: Address) -> Option<ImageVersion> {
+        if header_offset + IMAGE_HEADER_SIZE as Address > bank.size {
+            return None;
+        }
+
+        let mut bytes = [0_u8; IMAGE_HEADER_SIZE];
+        self.internal_memory
+            .read(bank.location + header_offset, &mut bytes)
+            .await
+            .ok()?;
+        ImageHeader::from_bytes(&bytes).map(|header| header.version)
+    }
+}
+
+/// Variant of `UpdateWriter` for flash with an async interface, obtained with
+/// [AsyncMoonbootManager::update_writer] or [AsyncMoonbootManager::resume_update_writer]
+pub struct AsyncUpdateWriter<'a, Flash: NorFlash, HardwareState: State, const PAGE_SIZE: usize> {
+    flash: &'a mut Flash,
+    unverified: &'a mut ImageSet,
+    // State to store the progress in, together with the identifier of the download
+    cursor: Option<(&'a mut HardwareState, u32)>,
+    stream: ImageStream<PAGE_SIZE>,
+}
+
+impl<'a, Flash: NorFlash, HardwareState: State, const PAGE_SIZE: usize>
+    AsyncUpdateWriter<'a, Flash, HardwareState, PAGE_SIZE>
+{
+    // Continue a download at the given offset, hashing the data already written to the flash
+    async fn resume(&mut self, offset: Address) -> Result<(), WriteError> {
+        self.stream.resume(offset)?;
+        self.run().await
+    }
+
+    /// See `UpdateWriter::written`
+    pub fn written(&self) -> Address {
+        self.stream.written
+    }
+
+    /// See `UpdateWriter::write`
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), WriteError> {
+        self.stream.check_write(data)?;
+
+        while !data.is_empty() {
+            data = self.stream.fill(data);
+            self.run().await?;
+        }
+
+        Ok(())
+    }
+
+    /// See `UpdateWriter::finish`
+    pub async fn finish(mut self) -> Result<Option<ImageHeader>, WriteError> {
+        self.stream.pad();
+        self.run().await?;
+
+        writer::finish(&self.stream, &mut self.cursor, self.unverified)
+    }
+
+    // Perform the flash operations the stream is waiting for
+    async fn run(&mut self) -> Result<(), WriteError> {
+        while let Some(operation) = self.stream.next_operation() {
+            match operation {
+                Operation::Read(address, buffer) => self.flash.read(address, buffer).await,
+                Operation::Erase(from, to) => self.flash.erase(from, to).await,
+                Operation::Write(address, data) => self.flash.write(address, data).await,
+            }
+            .map_err(|_| WriteError::Flash)?;
+
+            if let Some(offset) = self.stream.completed() {
+                writer::store_cursor(&mut self.cursor, self.stream.image, offset)?;
+            }
+        }
+        Ok(())
+    }
+}
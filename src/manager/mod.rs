@@ -1,12 +1,53 @@
 use crate::{
-    hardware::{processor::Processor, Config},
-    state::{State, Update},
+    hardware::{processor::Processor, Bank, Config, ConfigError, MAX_IMAGES},
+    history::{self, Event, Record},
+    image::{self, ImageVersion},
+    state::{ImageSet, MoonbootState, State, UpdateError},
+    Address,
 };
 
-use embedded_storage::{ReadStorage, Storage};
+use embedded_storage::{nor_flash::NorFlash, ReadStorage, Storage};
 
 use crate::log;
 
+mod reader;
+mod transitions;
+mod writer;
+pub use reader::BankReader;
+pub use writer::{UpdateWriter, WriteError};
+
+#[cfg(feature = "async")]
+mod asynch;
+#[cfg(feature = "async")]
+pub use asynch::{AsyncMoonbootManager, AsyncUpdateWriter};
+
+use transitions::Outcome;
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+/// Summary of the update state, see [MoonbootManager::status]
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootStatus {
+    /// Whether all running images are confirmed, so a reset does not revert any of them
+    pub confirmed: bool,
+    /// Images which are reverted on the next reset, either because they are not marked as
+    /// successful after an update, or because a rollback was requested
+    pub pending_revert: ImageSet,
+    /// Images the bootloader reverted to their previous version during the last boot
+    pub reverted: ImageSet,
+    /// Images an update was requested for, which the bootloader has not installed yet
+    pub pending_update: ImageSet,
+    /// Error which stopped the last update
+    pub error: Option<UpdateError>,
+    /// Versions of the images in the boot banks, indexed by image. Only available if
+    /// `Config::image_header_offset` is set and the image has a valid header.
+    pub boot_versions: [Option<ImageVersion>; MAX_IMAGES],
+    /// Versions of the images in the update banks, see `boot_versions`
+    pub update_versions: [Option<ImageVersion>; MAX_IMAGES],
+}
+
 /// Instantiate this in your application to enable mutation of the State specified in this and jump
 /// to the bootloader to apply any updates.
 pub struct MoonbootManager<
@@ -19,6 +60,8 @@ pub struct MoonbootManager<
     internal_memory: InternalMemory,
     state: HardwareState,
     processor: CPU,
+    // Images whose update bank is being written by an UpdateWriter which has not finished yet
+    unverified: ImageSet,
 }
 
 impl<
@@ -33,13 +76,18 @@ impl<
         internal_memory: InternalMemory,
         state: HardwareState,
         processor: CPU,
-    ) -> MoonbootManager<InternalMemory, HardwareState, CPU, INTERNAL_PAGE_SIZE> {
-        Self {
+    ) -> Result<MoonbootManager<InternalMemory, HardwareState, CPU, INTERNAL_PAGE_SIZE>, ConfigError>
+    {
+        config.validate(INTERNAL_PAGE_SIZE)?;
+        config.validate_capacity(internal_memory.capacity())?;
+
+        Ok(Self {
             config,
             internal_memory,
             state,
             processor,
-        }
+            unverified: ImageSet::empty(),
+        })
     }
 
     /// Destroy this instance of the boot manager and return access to the hardware peripheral
@@ -51,100 +99,317 @@ impl<
     /// succesful. If you do not do this, any reset will cause the bootloader to restore to the
     /// previous firmware image.
     pub fn mark_boot_successful(&mut self) -> Result<(), ()> {
-        let mut current_state = self.state.read();
-
-        log::info!(
-            "Application running, marking boot as successful. Current state: {:?}",
-            current_state
-        );
-
-        current_state.update = match current_state.update {
-            Update::None => {
-                log::info!("No Update was done.");
-                Update::None
-            }
-            Update::Revert(_) => {
-                log::info!("Software was updated, marking as successful.");
-                Update::None
-            }
-            _ => {
-                log::error!("There is an update queued, but it has not been installed yet. Did you skip the bootloader?");
-                return Err(());
-            }
-        };
+        self.transition(transitions::mark_boot_successful)
+    }
 
-        log::trace!("New state: {:?}", current_state);
+    /// Clear the error stored by the bootloader after a failed update, so further updates can be
+    /// installed. Does nothing if there is no error.
+    pub fn clear_error(&mut self) -> Result<(), ()> {
+        self.transition(transitions::clear_error)
+    }
 
-        self.state.write(current_state)
+    /// Mark a single image of a multi image update as successfully booted, e.g. once a
+    /// coprocessor reported its new firmware to be running. Only once all updated images are
+    /// marked, the update is complete. If the bootloader is entered before, all updated images are
+    /// reverted.
+    pub fn mark_image_successful(&mut self, image: u8) -> Result<(), ()> {
+        self.transition(|state| transitions::mark_image_successful(state, image))
     }
 
     // Upgrade firmware verifiying the given signature over the size of size.
     // Can only return an error or diverge (!, represented by Void while ! is not a type yet)
     pub fn update(&mut self) -> Result<void::Void, ()> {
         // Apply the update stored in the update bank
-        let bank = self.config.update_bank;
-        // TODO: Check size value!
-
-        log::info!("Update requested on slot {:?}", bank);
-
-        if bank.size > self.config.boot_bank.size {
-            log::error!(
-                "Requested update bank {:?} is larger than boot bank {:?}",
-                bank,
-                self.config.boot_bank
-            );
-            return Err(());
+        self.update_images(ImageSet::MAIN)
+    }
+
+    /// Exchange all given images with the contents of their update banks at once. If any of them
+    /// is not marked as successful after the update, all of them are reverted.
+    /// Can only return an error or diverge (!, represented by Void while ! is not a type yet)
+    pub fn update_images(&mut self, images: ImageSet) -> Result<void::Void, ()> {
+        self.schedule_images(images)?;
+
+        log::info!("Stored update request, jumping to bootloader! Geronimo!");
+
+        self.jump_to_bootloader()
+    }
+
+    /// Roll the main image back to the previous version stored in its update bank, even though
+    /// the running image is confirmed, e.g. because a release turned out to be bad. The bootloader
+    /// refuses the rollback if the update bank does not hold a valid image anymore.
+    /// Can only return an error or diverge (!, represented by Void while ! is not a type yet)
+    pub fn revert(&mut self) -> Result<void::Void, ()> {
+        self.revert_images(ImageSet::MAIN)
+    }
+
+    /// Roll the given images back at once, see [Self::revert]
+    /// Can only return an error or diverge (!, represented by Void while ! is not a type yet)
+    pub fn revert_images(&mut self, images: ImageSet) -> Result<void::Void, ()> {
+        self.schedule_revert(images)?;
+
+        log::info!("Stored rollback request, jumping to bootloader!");
+
+        self.jump_to_bootloader()
+    }
+
+    /// Request a rollback of the given images without jumping to the bootloader, see
+    /// [Self::revert]. Only possible once all running images are confirmed, fails if the
+    /// bootloader does not support rollbacks.
+    pub fn schedule_revert(&mut self, images: ImageSet) -> Result<(), ()> {
+        self.transition(|state| transitions::schedule_revert(state, images))
+    }
+
+    fn jump_to_bootloader(&mut self) -> Result<void::Void, ()> {
+        jump_to_bootloader(&self.config, &mut self.processor)
+    }
+
+    /// Request an update of the main image without jumping to the bootloader. The update is
+    /// installed the next time the bootloader runs, e.g. after a reset at a convenient time.
+    /// Fails while an [UpdateWriter] has not verified the update bank yet, or a resumable
+    /// download into it is unfinished. Writes through [Self::write_update_bank] or the [Storage]
+    /// implementation bypass this check.
+    pub fn schedule_update(&mut self) -> Result<(), ()> {
+        self.schedule_images(ImageSet::MAIN)
+    }
+
+    /// Request an update of the given images without jumping to the bootloader, see
+    /// [Self::schedule_update] and [Self::update_images]
+    pub fn schedule_images(&mut self, images: ImageSet) -> Result<(), ()> {
+        let (config, unverified) = (self.config, self.unverified);
+        self.transition(|state| transitions::schedule_images(&config, unverified, state, images))
+    }
+
+    /// Withdraw an update requested by [Self::schedule_update] or [Self::schedule_images] before
+    /// the bootloader installed it. Fails if no update is requested.
+    pub fn cancel_update(&mut self) -> Result<(), ()> {
+        self.transition(transitions::cancel_update)
+    }
+
+    /// Query what the bootloader did on the last boot and whether the running images still have to
+    /// be confirmed, e.g. to report it to a fleet backend
+    pub fn status(&mut self) -> BootStatus {
+        let mut status = transitions::status(&self.state.read());
+
+        let mut boot_versions = [None; MAX_IMAGES];
+        let mut update_versions = [None; MAX_IMAGES];
+        if let Some(header_offset) = self.config.image_header_offset {
+            for image in 0..MAX_IMAGES {
+                if let Some(banks) = self.config.image_banks(image as u8) {
+                    boot_versions[image] = image::read_header(
+                        &mut self.internal_memory,
+                        banks.boot_bank,
+                        header_offset,
+                    )
+                    .ok()
+                    .map(|header| header.version);
+                    update_versions[image] = image::read_header(
+                        &mut self.internal_memory,
+                        banks.update_bank,
+                        header_offset,
+                    )
+                    .ok()
+                    .map(|header| header.version);
+                }
+            }
         }
 
+        status.boot_versions = boot_versions;
+        status.update_versions = update_versions;
+        status
+    }
+
+    /// Read the update history, see [crate::history]. Index 0 is the most recent record, higher
+    /// indices go back in time. Returns None if no history is configured or there is no record
+    /// at the given index.
+    pub fn read_history(&mut self, index: u32) -> Option<Record> {
+        history::read(&mut self.internal_memory, self.config.history_bank?, index)
+    }
+
+    // Apply a transition to the shared state, writing it and recording the event if it changed
+    fn transition(
+        &mut self,
+        transition: impl FnOnce(&mut MoonbootState) -> Result<Outcome, ()>,
+    ) -> Result<(), ()> {
         let mut current_state = self.state.read();
+        if let Outcome::Changed(event) = transition(&mut current_state)? {
+            self.state.write(current_state)?;
+            if let Some(event) = event {
+                self.record(event);
+            }
+        }
+        Ok(())
+    }
 
-        if current_state.update != Update::None {
-            log::warn!(
-                "There is already an update in progress or queued: {:?}",
-                current_state.update
-            );
+    // Append an event to the update history, if one is configured. Errors are only logged.
+    fn record(&mut self, event: Event) {
+        if let Some(bank) = self.config.history_bank {
+            if history::append(&mut self.internal_memory, bank, event, false).is_err() {
+                log::warn!("Could not record {:?} in history", event);
+            }
         }
+    }
 
-        current_state.update = Update::Request(bank);
+    /// Read from the update bank of the given image. The [ReadStorage] implementation of the
+    /// manager reads from the update bank of the main image.
+    pub fn read_update_bank(&mut self, image: u8, offset: u32, bytes: &mut [u8]) -> Result<(), ()> {
+        let bank = self.config.image_banks(image).ok_or(())?.update_bank;
+        if offset > bank.size || offset + bytes.len() as u32 > bank.size {
+            Err(()) // TODO: We want better error types!
+        } else {
+            // TODO! fix
+            let bank_start = bank.location;
+            log::info!("Reading at {:x}[{:x}]", bank_start, offset);
+            match bank.memory_unit {
+                crate::hardware::MemoryUnit::Internal => {
+                    { self.internal_memory.read(bank_start + offset, bytes) }.map_err(|_| ())
+                }
+            }
+        }
+    }
 
-        self.state.write(current_state)?;
+    /// Read the image in the update bank of the given image through the flash driver, e.g. to
+    /// check its signature. If `Config::image_header_offset` is set and the bank holds a valid
+    /// header, only the image size from its header is read, otherwise the whole bank.
+    pub fn update_image_reader(&mut self, image: u8) -> Result<BankReader<'_, InternalMemory>, ()> {
+        let bank = self.config.image_banks(image).ok_or(())?.update_bank;
+        Ok(self.bank_reader(bank))
+    }
 
-        log::info!("Stored update request, jumping to bootloader! Geronimo!");
+    /// Read the running image in the boot bank of the given image, see
+    /// [Self::update_image_reader]
+    pub fn boot_image_reader(&mut self, image: u8) -> Result<BankReader<'_, InternalMemory>, ()> {
+        let bank = self.config.image_banks(image).ok_or(())?.boot_bank;
+        Ok(self.bank_reader(bank))
+    }
 
-        let bootloader_address = self.config.bootloader_bank.location;
+    fn bank_reader(&mut self, bank: Bank) -> BankReader<'_, InternalMemory> {
+        let size = self
+            .config
+            .image_header_offset
+            .and_then(|header_offset| {
+                image::read_header(&mut self.internal_memory, bank, header_offset).ok()
+            })
+            .map_or(bank.size, |header| header.size);
+        BankReader::new(&mut self.internal_memory, bank, size)
+    }
 
-        log::info!("Executing pre jump handler.");
-        extern "Rust" {
-            fn _moonboots_pre_jump();
-        }
-        unsafe {
-            _moonboots_pre_jump();
+    /// Get the update bank of the given image as a slice of memory, for flash which can be read
+    /// directly (execute in place) with `flash_origin` being the address the flash is mapped to,
+    /// see `LinkerConfig::flash_origin`. Returns None if the bank is not stored in such a memory
+    /// unit. Use [Self::update_image_reader] otherwise.
+    ///
+    /// # Safety
+    ///
+    /// The flash holding the bank has to be memory mapped at `flash_origin` and readable for the
+    /// lifetime of the slice.
+    pub unsafe fn mapped_update_bank(&self, image: u8, flash_origin: Address) -> Option<&[u8]> {
+        let bank = self.config.image_banks(image)?.update_bank;
+        match bank.memory_unit {
+            crate::hardware::MemoryUnit::Internal => Some(core::slice::from_raw_parts(
+                (flash_origin + bank.location) as *const u8,
+                bank.size as usize,
+            )),
         }
+    }
 
-        self.processor.do_jump(bootloader_address)
+    /// Write to the update bank of the given image. The [Storage] implementation of the manager
+    /// writes to the update bank of the main image. Unlike an [UpdateWriter], this neither
+    /// verifies the image nor keeps it from being scheduled while it is incomplete.
+    pub fn write_update_bank(&mut self, image: u8, offset: u32, bytes: &[u8]) -> Result<(), ()> {
+        let bank = self.config.image_banks(image).ok_or(())?.update_bank;
+        if offset > bank.size || offset + bytes.len() as u32 > bank.size {
+            Err(()) // TODO: We want better error types!
+        } else {
+            // TODO! fix
+            let bank_start = bank.location;
+            log::info!("Writing at {:x}[{:x}]", bank_start, offset);
+            match bank.memory_unit {
+                crate::hardware::MemoryUnit::Internal => {
+                    { self.internal_memory.write(bank_start + offset, bytes) }.map_err(|_| ())
+                }
+            }
+        }
     }
 }
 
-/// Easily get read access to the update bank
 impl<
-        InternalMemory: Storage,
+        InternalMemory: Storage + NorFlash,
         HardwareState: State,
         CPU: Processor,
         const INTERNAL_PAGE_SIZE: usize,
-    > core::convert::AsRef<[u8]>
-    for MoonbootManager<InternalMemory, HardwareState, CPU, INTERNAL_PAGE_SIZE>
+    > MoonbootManager<InternalMemory, HardwareState, CPU, INTERNAL_PAGE_SIZE>
 {
-    #[inline]
-    fn as_ref(&self) -> &[u8] {
-        unsafe {
-            core::slice::from_raw_parts(
-                self.config.update_bank.location as *const u8,
-                self.config.update_bank.size as usize,
-            )
+    /// Start writing a new image into the update bank of the given image, see [UpdateWriter].
+    /// Updating the image is refused until [UpdateWriter::finish] verified the new image.
+    pub fn update_writer(
+        &mut self,
+        image: u8,
+    ) -> Result<UpdateWriter<'_, InternalMemory, HardwareState, INTERNAL_PAGE_SIZE>, WriteError>
+    {
+        self.start_writer(image, None)
+    }
+
+    /// Like [Self::update_writer], but store the progress in the shared state, so the download can
+    /// be continued after a reset. `id` identifies the image being downloaded, e.g. its build
+    /// number. If the stored progress belongs to the same image and id, the writer continues at
+    /// [UpdateWriter::written], otherwise it starts over.
+    pub fn resume_update_writer(
+        &mut self,
+        image: u8,
+        id: u32,
+    ) -> Result<UpdateWriter<'_, InternalMemory, HardwareState, INTERNAL_PAGE_SIZE>, WriteError>
+    {
+        self.start_writer(image, Some(id))
+    }
+
+    fn start_writer(
+        &mut self,
+        image: u8,
+        id: Option<u32>,
+    ) -> Result<UpdateWriter<'_, InternalMemory, HardwareState, INTERNAL_PAGE_SIZE>, WriteError>
+    {
+        let bank = self
+            .config
+            .image_banks(image)
+            .ok_or(WriteError::InvalidImage)?
+            .update_bank;
+
+        let resume_offset = writer::prepare_cursor(&mut self.state, image, id)?;
+
+        let mut writer = UpdateWriter::new(
+            &mut self.internal_memory,
+            &mut self.unverified,
+            id.map(|id| (&mut self.state, id)),
+            image,
+            bank,
+            self.config.image_header_offset,
+        )?;
+        if resume_offset != 0 {
+            writer.resume(resume_offset)?;
         }
+
+        Ok(writer)
     }
 }
 
+// Run the pre jump handler and jump to the bootloader, shared by the blocking and async manager
+fn jump_to_bootloader<CPU: Processor>(
+    config: &Config,
+    processor: &mut CPU,
+) -> Result<void::Void, ()> {
+    let bootloader_address = config.bootloader_bank.location;
+
+    log::info!("Executing pre jump handler.");
+    extern "Rust" {
+        fn _moonboots_pre_jump();
+    }
+    unsafe {
+        _moonboots_pre_jump();
+    }
+
+    processor.do_jump(bootloader_address)
+}
+
 /// Read Access to the current update target slot
 impl<
         InternalMemory: Storage,
@@ -156,19 +421,7 @@ impl<
     type Error = (); // TODO
 
     fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
-        let bank = self.config.update_bank; // For now we always write updates to this bank.
-        if offset > bank.size || offset + bytes.len() as u32 > bank.size {
-            Err(()) // TODO: We want better error types!
-        } else {
-            // TODO! fix
-            let bank_start = bank.location;
-            log::info!("Writing at {:x}[{:x}]", bank_start, offset);
-            match bank.memory_unit {
-                crate::hardware::MemoryUnit::Internal => {
-                    { self.internal_memory.read(bank_start + offset, bytes) }.map_err(|_| ())
-                }
-            }
-        }
+        self.read_update_bank(0, offset, bytes)
     }
 
     fn capacity(&self) -> usize {
@@ -176,7 +429,7 @@ impl<
     }
 }
 
-/// Write Access to the current update target slot
+/// Write Access to the current update target slot, see [MoonbootManager::write_update_bank]
 impl<
         InternalMemory: Storage,
         HardwareState: State,
@@ -185,18 +438,6 @@ impl<
     > Storage for MoonbootManager<InternalMemory, HardwareState, CPU, INTERNAL_PAGE_SIZE>
 {
     fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
-        let bank = self.config.update_bank; // For now we always write updates to this bank.
-        if offset > bank.size || offset + bytes.len() as u32 > bank.size {
-            Err(()) // TODO: We want better error types!
-        } else {
-            // TODO! fix
-            let bank_start = bank.location;
-            log::info!("Writing at {:x}[{:x}]", bank_start, offset);
-            match bank.memory_unit {
-                crate::hardware::MemoryUnit::Internal => {
-                    { self.internal_memory.write(bank_start + offset, bytes) }.map_err(|_| ())
-                }
-            }
-        }
+        self.write_update_bank(0, offset, bytes)
     }
 }
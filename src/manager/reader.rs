@@ -0,0 +1,65 @@
+use crate::{hardware::Bank, Address};
+
+use embedded_storage::ReadStorage;
+
+/// Reads the contents of a bank in chunks through the flash driver, so it works no matter whether
+/// and where the bank is memory mapped. Obtain it with `MoonbootManager::update_image_reader` or
+/// `MoonbootManager::boot_image_reader`.
+pub struct BankReader<'a, Memory: ReadStorage> {
+    memory: &'a mut Memory,
+    bank: Bank,
+    size: Address,
+    position: Address,
+}
+
+impl<'a, Memory: ReadStorage> BankReader<'a, Memory> {
+    pub(crate) fn new(memory: &'a mut Memory, bank: Bank, size: Address) -> Self {
+        Self {
+            memory,
+            bank,
+            size: core::cmp::min(size, bank.size),
+            position: 0,
+        }
+    }
+
+    /// Number of bytes which can be read in total
+    pub fn size(&self) -> Address {
+        self.size
+    }
+
+    /// Offset from the start of the bank the next read starts at
+    pub fn position(&self) -> Address {
+        self.position
+    }
+
+    /// Continue reading at the given offset from the start of the bank
+    pub fn seek(&mut self, position: Address) -> Result<(), ()> {
+        if position > self.size {
+            return Err(());
+        }
+        self.position = position;
+        Ok(())
+    }
+
+    /// Read the next chunk into `bytes`, returning the number of bytes read. Returns 0 once
+    /// everything was read.
+    pub fn read(&mut self, bytes: &mut [u8]) -> Result<usize, ()> {
+        let length = core::cmp::min(bytes.len() as Address, self.size - self.position);
+        if length == 0 {
+            return Ok(0);
+        }
+
+        match self.bank.memory_unit {
+            crate::hardware::MemoryUnit::Internal => self
+                .memory
+                .read(
+                    self.bank.location + self.position,
+                    &mut bytes[..length as usize],
+                )
+                .map_err(|_| ())?,
+        }
+        self.position += length;
+
+        Ok(length as usize)
+    }
+}
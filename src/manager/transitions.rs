@@ -0,0 +1,199 @@
+// Changes of the shared state requested by the application. They are shared by the blocking and
+// the async manager, which only differ in how they access the flash.
+
+use crate::{
+    hardware::{Config, MAX_IMAGES},
+    history::Event,
+    state::{ImageSet, MoonbootState, Update},
+};
+
+use super::BootStatus;
+
+use crate::log;
+
+/// Result of a successful transition
+pub(super) enum Outcome {
+    /// The state is unchanged and does not have to be written
+    Unchanged,
+    /// The state has to be written, after which the event, if any, is recorded in the history
+    Changed(Option<Event>),
+}
+
+pub(super) fn mark_boot_successful(state: &mut MoonbootState) -> Result<Outcome, ()> {
+    log::info!(
+        "Application running, marking boot as successful. Current state: {:?}",
+        state
+    );
+
+    let event = match state.update {
+        Update::None => {
+            log::info!("No Update was done.");
+            None
+        }
+        Update::Revert(images) => {
+            log::info!("Software was updated, marking as successful.");
+            Some(Event::BootConfirmed(images))
+        }
+        _ => {
+            log::error!("There is an update queued, but it has not been installed yet. Did you skip the bootloader?");
+            return Err(());
+        }
+    };
+    state.update = Update::None;
+    state.confirmed = ImageSet::empty();
+
+    log::trace!("New state: {:?}", state);
+
+    Ok(Outcome::Changed(event))
+}
+
+pub(super) fn clear_error(state: &mut MoonbootState) -> Result<Outcome, ()> {
+    match state.update {
+        Update::Error(err) => {
+            log::info!("Clearing update error {:?}", err);
+            state.update = Update::None;
+            Ok(Outcome::Changed(None))
+        }
+        _ => Ok(Outcome::Unchanged),
+    }
+}
+
+pub(super) fn mark_image_successful(state: &mut MoonbootState, image: u8) -> Result<Outcome, ()> {
+    log::info!(
+        "Marking image {} as successful. Current state: {:?}",
+        image,
+        state
+    );
+
+    match state.update {
+        Update::None => {
+            log::info!("No Update was done.");
+            return Ok(Outcome::Unchanged);
+        }
+        Update::Revert(images) if images.contains(image) => {
+            state.confirmed = state.confirmed.with(image);
+            if images.is_subset(state.confirmed) {
+                log::info!("All updated images are running, marking as successful.");
+                state.update = Update::None;
+                state.confirmed = ImageSet::empty();
+            }
+        }
+        Update::Revert(_) => {
+            log::info!("Image {} was not updated.", image);
+            return Ok(Outcome::Unchanged);
+        }
+        _ => {
+            log::error!("There is an update queued, but it has not been installed yet. Did you skip the bootloader?");
+            return Err(());
+        }
+    }
+
+    log::trace!("New state: {:?}", state);
+
+    Ok(Outcome::Changed(Some(Event::BootConfirmed(
+        ImageSet::empty().with(image),
+    ))))
+}
+
+pub(super) fn schedule_revert(state: &mut MoonbootState, images: ImageSet) -> Result<Outcome, ()> {
+    if state.update != Update::None {
+        log::error!(
+            "Cannot roll back while an update is pending: {:?}",
+            state.update
+        );
+        return Err(());
+    }
+
+    log::info!("Rollback requested on images {:?}", images);
+    state.update = Update::Rollback(images);
+
+    Ok(Outcome::Changed(Some(Event::RollbackRequested(images))))
+}
+
+pub(super) fn schedule_images(
+    config: &Config,
+    unverified: ImageSet,
+    state: &mut MoonbootState,
+    images: ImageSet,
+) -> Result<Outcome, ()> {
+    log::info!("Update requested on images {:?}", images);
+
+    if !images.intersection(unverified).is_empty() {
+        log::error!(
+            "Update banks of images {:?} are still being written",
+            images.intersection(unverified)
+        );
+        return Err(());
+    }
+
+    // A download interrupted by a reset is only known from the stored progress
+    if state.download.offset != 0 && images.contains(state.download.image) {
+        log::error!(
+            "Download into the update bank of image {} is unfinished: {:?}",
+            state.download.image,
+            state.download
+        );
+        return Err(());
+    }
+
+    // The sizes of the banks were checked by Config::validate already
+    if let Some(image) = images
+        .iter()
+        .find(|image| config.image_banks(*image).is_none())
+    {
+        log::error!("Requested image {} is not configured", image);
+        return Err(());
+    }
+
+    if state.update != Update::None {
+        log::warn!(
+            "There is already an update in progress or queued: {:?}",
+            state.update
+        );
+    }
+
+    state.update = Update::Request(images);
+
+    Ok(Outcome::Changed(Some(Event::UpdateRequested(images))))
+}
+
+pub(super) fn cancel_update(state: &mut MoonbootState) -> Result<Outcome, ()> {
+    match state.update {
+        Update::Request(images) => {
+            log::info!("Cancelling update of images {:?}", images);
+            state.update = Update::None;
+            Ok(Outcome::Changed(Some(Event::UpdateCancelled(images))))
+        }
+        _ => {
+            log::error!("No update requested, cannot cancel: {:?}", state.update);
+            Err(())
+        }
+    }
+}
+
+/// The status as far as it can be derived from the state, without any image versions
+pub(super) fn status(state: &MoonbootState) -> BootStatus {
+    let (confirmed, pending_revert, pending_update, error) = match state.update {
+        Update::None => (true, ImageSet::empty(), ImageSet::empty(), None),
+        Update::Revert(images) => (
+            false,
+            images.difference(state.confirmed),
+            ImageSet::empty(),
+            None,
+        ),
+        Update::Request(images) => (true, ImageSet::empty(), images, None),
+        Update::Exchanging(_) => (false, ImageSet::empty(), ImageSet::empty(), None),
+        Update::Error(err) => (true, ImageSet::empty(), ImageSet::empty(), Some(err)),
+        Update::Rollback(images) => (true, images, ImageSet::empty(), None),
+    };
+
+    BootStatus {
+        confirmed,
+        pending_revert,
+        reverted: state.reverted,
+        pending_update,
+        error,
+        boot_versions: [None; MAX_IMAGES],
+        update_versions: [None; MAX_IMAGES],
+    }
+}
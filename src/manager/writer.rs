@@ -0,0 +1,735 @@
+use crate::{
+    hardware::Bank,
+    image::{ImageHeader, IMAGE_HEADER_SIZE},
+    state::{DownloadCursor, ImageSet, State},
+    Address,
+};
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use embedded_storage::nor_flash::NorFlash;
+use sha2::{Digest, Sha256};
+
+use crate::log;
+
+/// Errors that can occur while writing an update with an [UpdateWriter]
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteError {
+    /// The image index is not configured
+    InvalidImage,
+    /// The update bank is not aligned to the erase size of the flash, or the page size is not a
+    /// multiple of its write size
+    Misaligned,
+    /// The image does not fit into the update bank
+    OutOfBounds,
+    /// Erasing, writing or reading back the flash failed
+    Flash,
+    /// The download progress could not be stored
+    State,
+    /// The image does not contain a valid header at `Config::image_header_offset`
+    InvalidHeader,
+    /// The amount of data written differs from the size in the image header
+    SizeMismatch,
+    /// The hash of the written data does not match the one in the image header
+    HashMismatch,
+}
+
+/// Writes an update into the update bank of an image as it arrives, e.g. from a network
+/// connection. Sectors are erased right before they are written to, data is buffered until a full
+/// page can be written and hashed on the fly, so the image can be verified without reading it
+/// back. Obtain it with `MoonbootManager::update_writer`, the image can only be updated once
+/// [UpdateWriter::finish] succeeded. If obtained with `MoonbootManager::resume_update_writer`, the
+/// progress is stored in the shared state, so the download can be continued after a reset.
+pub struct UpdateWriter<'a, Flash: NorFlash, HardwareState: State, const PAGE_SIZE: usize> {
+    flash: &'a mut Flash,
+    unverified: &'a mut ImageSet,
+    // State to store the progress in, together with the identifier of the download
+    cursor: Option<(&'a mut HardwareState, u32)>,
+    stream: ImageStream<PAGE_SIZE>,
+}
+
+impl<'a, Flash: NorFlash, HardwareState: State, const PAGE_SIZE: usize>
+    UpdateWriter<'a, Flash, HardwareState, PAGE_SIZE>
+{
+    pub(crate) fn new(
+        flash: &'a mut Flash,
+        unverified: &'a mut ImageSet,
+        cursor: Option<(&'a mut HardwareState, u32)>,
+        image: u8,
+        bank: Bank,
+        header_offset: Option<Address>,
+    ) -> Result<Self, WriteError> {
+        let stream = ImageStream::new(
+            image,
+            bank,
+            header_offset,
+            Flash::WRITE_SIZE,
+            Flash::ERASE_SIZE,
+        )?;
+
+        // The previous contents of the update bank are about to be destroyed
+        *unverified = unverified.with(image);
+
+        Ok(Self {
+            flash,
+            unverified,
+            cursor,
+            stream,
+        })
+    }
+
+    // Continue a download at the given offset, hashing the data already written to the flash
+    pub(crate) fn resume(&mut self, offset: Address) -> Result<(), WriteError> {
+        self.stream.resume(offset)?;
+        self.run()
+    }
+
+    /// Number of bytes written so far. After resuming a download, continue with the data at this
+    /// offset of the image.
+    pub fn written(&self) -> Address {
+        self.stream.written
+    }
+
+    /// Append data to the image
+    pub fn write(&mut self, mut data: &[u8]) -> Result<(), WriteError> {
+        self.stream.check_write(data)?;
+
+        while !data.is_empty() {
+            data = self.stream.fill(data);
+            self.run()?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the remaining buffered data and verify the image against its header, if
+    /// `Config::image_header_offset` is set. Only afterwards, the image can be updated.
+    pub fn finish(mut self) -> Result<Option<ImageHeader>, WriteError> {
+        self.stream.pad();
+        self.run()?;
+
+        finish(&self.stream, &mut self.cursor, self.unverified)
+    }
+
+    // Perform the flash operations the stream is waiting for
+    fn run(&mut self) -> Result<(), WriteError> {
+        while let Some(operation) = self.stream.next_operation() {
+            match operation {
+                Operation::Read(address, buffer) => self.flash.read(address, buffer),
+                Operation::Erase(from, to) => self.flash.erase(from, to),
+                Operation::Write(address, data) => NorFlash::write(self.flash, address, data),
+            }
+            .map_err(|_| WriteError::Flash)?;
+
+            if let Some(offset) = self.stream.completed() {
+                store_cursor(&mut self.cursor, self.stream.image, offset)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Verify a stream with everything flushed and mark the image as updatable if it is valid
+pub(crate) fn finish<HardwareState: State, const PAGE_SIZE: usize>(
+    stream: &ImageStream<PAGE_SIZE>,
+    cursor: &mut Option<(&mut HardwareState, u32)>,
+    unverified: &mut ImageSet,
+) -> Result<Option<ImageHeader>, WriteError> {
+    // Nothing left to resume, a broken image has to be written from the start again
+    let verified = stream.verify();
+    store_cursor(cursor, stream.image, 0)?;
+    let header = verified?;
+    *unverified = unverified.difference(ImageSet::empty().with(stream.image));
+
+    Ok(header)
+}
+
+// Store the download progress in the shared state, if the download is resumable
+pub(crate) fn store_cursor<HardwareState: State>(
+    cursor: &mut Option<(&mut HardwareState, u32)>,
+    image: u8,
+    offset: Address,
+) -> Result<(), WriteError> {
+    if let Some((state, id)) = cursor {
+        let mut current_state = state.read();
+        current_state.download = DownloadCursor {
+            image,
+            id: *id,
+            offset,
+        };
+        state.write(current_state).map_err(|_| WriteError::State)?;
+    }
+    Ok(())
+}
+
+// Returns the offset to resume a download of `image` at, discarding the stored progress if it
+// belongs to a different download into the same bank. Without `id`, the download is not
+// resumable.
+pub(crate) fn prepare_cursor<HardwareState: State>(
+    state: &mut HardwareState,
+    image: u8,
+    id: Option<u32>,
+) -> Result<Address, WriteError> {
+    let mut current_state = state.read();
+    let cursor = current_state.download;
+    let resume_offset = match id {
+        Some(id) if cursor.image == image && cursor.id == id => cursor.offset,
+        _ => 0,
+    };
+
+    // The bank is written from the start, so a previous download cannot be continued anymore
+    if resume_offset == 0 && cursor.image == image && cursor.offset != 0 {
+        log::info!("Discarding download progress {:?}", cursor);
+        current_state.download = Default::default();
+        state.write(current_state).map_err(|_| WriteError::State)?;
+    }
+
+    Ok(resume_offset)
+}
+
+// Flash operation an ImageStream waits for, with absolute addresses. Report its success with
+// ImageStream::completed.
+pub(crate) enum Operation<'a> {
+    Read(Address, &'a mut [u8]),
+    Erase(Address, Address),
+    Write(Address, &'a [u8]),
+}
+
+// What an ImageStream does next
+#[derive(Clone, Copy)]
+enum Phase {
+    // Waiting for more data
+    Idle,
+    // Reading the rest of the sector a download is resumed in from position on. A reset between
+    // writing the flash and storing the progress leaves data behind the offset, which has to be
+    // erased again.
+    CheckErased { offset: Address, position: Address },
+    // Reading the data in front of the offset a download is resumed at from position on to hash it
+    Rehash { offset: Address, position: Address },
+    // Writing the buffer, erasing the sectors it ends up in first
+    Flush,
+}
+
+// Bookkeeping of an update being written, independent of how the flash is accessed. The writers
+// feed it data and perform the operations returned by next_operation until there are none left.
+pub(crate) struct ImageStream<const PAGE_SIZE: usize> {
+    pub(crate) image: u8,
+    pub(crate) bank: Bank,
+    write_size: usize,
+    erase_size: Address,
+    // Bytes received so far, including the buffered ones
+    pub(crate) written: Address,
+    // Bytes written to the flash, always a multiple of the page size until finished
+    pub(crate) flushed: Address,
+    // Bytes erased from the start of the bank
+    erased: Address,
+    // Data to write, or data read back while resuming
+    buffer: [u8; PAGE_SIZE],
+    buffered: usize,
+    phase: Phase,
+    pub(crate) digest: ImageDigest,
+}
+
+impl<const PAGE_SIZE: usize> ImageStream<PAGE_SIZE> {
+    pub(crate) fn new(
+        image: u8,
+        bank: Bank,
+        header_offset: Option<Address>,
+        write_size: usize,
+        erase_size: usize,
+    ) -> Result<Self, WriteError> {
+        if !bank.location.is_multiple_of(erase_size as Address)
+            || PAGE_SIZE == 0
+            || !PAGE_SIZE.is_multiple_of(write_size)
+        {
+            return Err(WriteError::Misaligned);
+        }
+
+        Ok(Self {
+            image,
+            bank,
+            write_size,
+            erase_size: erase_size as Address,
+            written: 0,
+            flushed: 0,
+            erased: 0,
+            buffer: [0; PAGE_SIZE],
+            buffered: 0,
+            phase: Phase::Idle,
+            digest: ImageDigest::new(header_offset),
+        })
+    }
+
+    // Continue a download at the given offset, reading back and hashing the data already written
+    pub(crate) fn resume(&mut self, offset: Address) -> Result<(), WriteError> {
+        if !offset.is_multiple_of(PAGE_SIZE as Address) || offset > self.bank.size {
+            return Err(WriteError::Misaligned);
+        }
+
+        log::info!("Resuming download of image {} at {}", self.image, offset);
+        self.phase = Phase::CheckErased {
+            offset,
+            position: offset,
+        };
+        Ok(())
+    }
+
+    // Check the data fits into the bank and hash it
+    pub(crate) fn check_write(&mut self, data: &[u8]) -> Result<(), WriteError> {
+        if self.written as u64 + data.len() as u64 > self.bank.size as u64 {
+            return Err(WriteError::OutOfBounds);
+        }
+
+        self.digest.update(self.written, data);
+        self.written += data.len() as Address;
+        Ok(())
+    }
+
+    // Buffer as much of the data as fits into the page, returning the rest. A full page is
+    // flushed next.
+    pub(crate) fn fill<'d>(&mut self, data: &'d [u8]) -> &'d [u8] {
+        let length = core::cmp::min(PAGE_SIZE - self.buffered, data.len());
+        self.buffer[self.buffered..self.buffered + length].copy_from_slice(&data[..length]);
+        self.buffered += length;
+        if self.buffered == PAGE_SIZE {
+            self.phase = Phase::Flush;
+        }
+        &data[length..]
+    }
+
+    // Pad the last write to the write size with the erased value and flush it next
+    pub(crate) fn pad(&mut self) {
+        if self.buffered == 0 {
+            return;
+        }
+        let padded = self.buffered.div_ceil(self.write_size) * self.write_size;
+        self.buffer[self.buffered..padded].fill(0xff);
+        self.buffered = padded;
+        self.phase = Phase::Flush;
+    }
+
+    // The flash operation to perform next, if any
+    pub(crate) fn next_operation(&mut self) -> Option<Operation<'_>> {
+        // Resuming is done once everything is read
+        if let Phase::CheckErased { offset, position } = self.phase {
+            if position >= self.sector_end(offset) {
+                self.phase = Phase::Rehash {
+                    offset,
+                    position: 0,
+                };
+            }
+        }
+        if let Phase::Rehash { offset, position } = self.phase {
+            if position >= offset {
+                self.resumed(offset);
+            }
+        }
+
+        match self.phase {
+            Phase::Idle => None,
+            Phase::CheckErased { .. } | Phase::Rehash { .. } => {
+                let (position, end) = self.pending_read()?;
+                Some(Operation::Read(
+                    self.bank.location + position,
+                    &mut self.buffer[..(end - position) as usize],
+                ))
+            }
+            Phase::Flush => match self.pending_erase() {
+                Some((from, to)) => {
+                    log::trace!("Erasing update bank from {} to {}", from, to);
+                    Some(Operation::Erase(
+                        self.bank.location + from,
+                        self.bank.location + to,
+                    ))
+                }
+                None => Some(Operation::Write(
+                    self.bank.location + self.flushed,
+                    &self.buffer[..self.buffered],
+                )),
+            },
+        }
+    }
+
+    // The operation returned by next_operation succeeded. Returns the offset a download can be
+    // continued at if it changed.
+    pub(crate) fn completed(&mut self) -> Option<Address> {
+        match self.phase {
+            Phase::Idle => None,
+            Phase::CheckErased { offset, .. } => {
+                let (position, end) = self.pending_read()?;
+                let length = (end - position) as usize;
+                self.phase = if self.buffer[..length].iter().any(|byte| *byte != 0xff) {
+                    Phase::Rehash {
+                        offset: self.rewind(offset),
+                        position: 0,
+                    }
+                } else {
+                    Phase::CheckErased {
+                        offset,
+                        position: end,
+                    }
+                };
+                None
+            }
+            Phase::Rehash { offset, .. } => {
+                let (position, end) = self.pending_read()?;
+                self.digest
+                    .update(position, &self.buffer[..(end - position) as usize]);
+                self.phase = Phase::Rehash {
+                    offset,
+                    position: end,
+                };
+                None
+            }
+            Phase::Flush => match self.pending_erase() {
+                Some((_, to)) => {
+                    self.erased = to;
+                    None
+                }
+                None => {
+                    self.flushed += self.buffered as Address;
+                    self.buffered = 0;
+                    self.phase = Phase::Idle;
+                    self.resume_offset()
+                }
+            },
+        }
+    }
+
+    // End of the sector containing the offset, relative to the start of the bank
+    fn sector_end(&self, offset: Address) -> Address {
+        core::cmp::min(
+            offset.div_ceil(self.erase_size) * self.erase_size,
+            self.bank.size,
+        )
+    }
+
+    // Offset to continue a download at if the sector it stopped at holds data written after the
+    // progress was stored. As the sector has to be erased again, the download continues at the
+    // start of the sector, or earlier to keep the offset a multiple of the page size.
+    fn rewind(&self, offset: Address) -> Address {
+        let mut step = self.erase_size;
+        while !step.is_multiple_of(PAGE_SIZE as Address) {
+            step += self.erase_size;
+        }
+        let rewound = offset - offset % step;
+        log::warn!(
+            "Update bank is written past {}, continuing at {}",
+            offset,
+            rewound
+        );
+        rewound
+    }
+
+    // The data up to offset was read back and hashed, continue writing after it
+    fn resumed(&mut self, offset: Address) {
+        // Only the sector the download stopped in is partially written, the rest is still erased
+        self.written = offset;
+        self.flushed = offset;
+        self.erased = self.sector_end(offset);
+        self.phase = Phase::Idle;
+    }
+
+    // Range of the bank to read back next while resuming, relative to its start
+    fn pending_read(&self) -> Option<(Address, Address)> {
+        let (position, end) = match self.phase {
+            Phase::CheckErased { offset, position } => (position, self.sector_end(offset)),
+            Phase::Rehash { offset, position } => (position, offset),
+            _ => return None,
+        };
+        Some((
+            position,
+            core::cmp::min(position + PAGE_SIZE as Address, end),
+        ))
+    }
+
+    // Range of the bank to erase before the buffer can be written, relative to its start
+    fn pending_erase(&self) -> Option<(Address, Address)> {
+        let end = self.flushed + self.buffered as Address;
+        if end <= self.erased {
+            return None;
+        }
+
+        let erase_end = core::cmp::min(
+            end.div_ceil(self.erase_size) * self.erase_size,
+            self.bank.size,
+        );
+        Some((self.erased, erase_end))
+    }
+
+    // Offset a download can be continued at, unless the last write was padded
+    fn resume_offset(&self) -> Option<Address> {
+        if self.flushed.is_multiple_of(PAGE_SIZE as Address) && self.flushed <= self.written {
+            Some(self.flushed)
+        } else {
+            None
+        }
+    }
+
+    // Verify the written image against its header
+    pub(crate) fn verify(&self) -> Result<Option<ImageHeader>, WriteError> {
+        let header = match self.digest.header_offset {
+            Some(header_offset) => {
+                if self.written < header_offset + IMAGE_HEADER_SIZE as Address {
+                    return Err(WriteError::InvalidHeader);
+                }
+                let header = ImageHeader::from_bytes(&self.digest.header)
+                    .ok_or(WriteError::InvalidHeader)?;
+                if header.size != self.written {
+                    log::error!(
+                        "Received {} bytes, but the image header specifies {}",
+                        self.written,
+                        header.size
+                    );
+                    return Err(WriteError::SizeMismatch);
+                }
+                if self.digest.hasher.clone().finalize().as_slice() != header.hash {
+                    return Err(WriteError::HashMismatch);
+                }
+                Some(header)
+            }
+            None => None,
+        };
+
+        log::info!(
+            "Update of image {} with {} bytes written",
+            self.image,
+            self.written
+        );
+
+        Ok(header)
+    }
+}
+
+// Hash of an image, with the image header captured instead of hashed
+pub(crate) struct ImageDigest {
+    header_offset: Option<Address>,
+    pub(crate) hasher: Sha256,
+    pub(crate) header: [u8; IMAGE_HEADER_SIZE],
+}
+
+impl ImageDigest {
+    fn new(header_offset: Option<Address>) -> Self {
+        Self {
+            header_offset,
+            hasher: Sha256::new(),
+            header: [0; IMAGE_HEADER_SIZE],
+        }
+    }
+
+    // Hash the data received at the given offset of the image
+    pub(crate) fn update(&mut self, offset: Address, data: &[u8]) {
+        let (header_start, header_end) = match self.header_offset {
+            Some(header_offset) => (
+                header_offset as u64,
+                header_offset as u64 + IMAGE_HEADER_SIZE as u64,
+            ),
+            None => {
+                self.hasher.update(data);
+                return;
+            }
+        };
+
+        // Split the data into the parts before, inside and after the header
+        let start = offset as u64;
+        let end = start + data.len() as u64;
+        let before = (header_start.clamp(start, end) - start) as usize;
+        let after = (header_end.clamp(start, end) - start) as usize;
+
+        self.hasher.update(&data[..before]);
+        if after > before {
+            let header_index = (start + before as u64 - header_start) as usize;
+            self.header[header_index..header_index + after - before]
+                .copy_from_slice(&data[before..after]);
+        }
+        self.hasher.update(&data[after..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::{hardware::MemoryUnit, image::ImageVersion};
+    use std::vec::Vec;
+
+    const HEADER_OFFSET: Address = 0x40;
+    const IMAGE_SIZE: usize = 0x200;
+
+    fn stream(header_offset: Option<Address>) -> ImageStream<64> {
+        let bank = Bank {
+            location: 0x1000,
+            size: 0x1000,
+            memory_unit: MemoryUnit::Internal,
+        };
+        ImageStream::new(0, bank, header_offset, 4, 0x100).unwrap()
+    }
+
+    // Image with a valid header at HEADER_OFFSET
+    fn image() -> ([u8; IMAGE_SIZE], ImageHeader) {
+        let mut image = [0_u8; IMAGE_SIZE];
+        for (i, byte) in image.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        let header_end = HEADER_OFFSET as usize + IMAGE_HEADER_SIZE;
+        let mut hasher = Sha256::new();
+        hasher.update(&image[..HEADER_OFFSET as usize]);
+        hasher.update(&image[header_end..]);
+
+        let header = ImageHeader {
+            version: ImageVersion {
+                major: 1,
+                minor: 2,
+                patch: 3,
+            },
+            size: IMAGE_SIZE as u32,
+            dependency: None,
+            hash: hasher.finalize().into(),
+        };
+        image[HEADER_OFFSET as usize..header_end].copy_from_slice(&header.to_bytes());
+        (image, header)
+    }
+
+    #[test]
+    fn digest_skips_header_split_across_chunks() {
+        let (image, header) = image();
+        // Chunk boundaries inside, at and around the header
+        for chunk_size in [1, 7, 13, 0x40, 0x41, 100, IMAGE_SIZE] {
+            let mut stream = stream(Some(HEADER_OFFSET));
+            let mut offset = 0;
+            for chunk in image.chunks(chunk_size) {
+                stream.digest.update(offset, chunk);
+                offset += chunk.len() as Address;
+            }
+            stream.written = offset;
+
+            assert_eq!(
+                stream.digest.header,
+                header.to_bytes(),
+                "chunks of {}",
+                chunk_size
+            );
+            assert_eq!(
+                stream.verify(),
+                Ok(Some(header)),
+                "chunks of {}",
+                chunk_size
+            );
+        }
+    }
+
+    #[test]
+    fn digest_without_header_hashes_everything() {
+        let (image, _) = image();
+        let mut stream = stream(None);
+        for (i, chunk) in image.chunks(13).enumerate() {
+            stream.digest.update(i as Address * 13, chunk);
+        }
+
+        assert_eq!(
+            stream.digest.hasher.finalize().as_slice(),
+            Sha256::digest(image).as_slice()
+        );
+    }
+
+    // Perform the operations of the stream on the bank, returning the offsets stored as progress
+    fn run(stream: &mut ImageStream<64>, bank: &mut [u8]) -> Vec<Address> {
+        let mut offsets = Vec::new();
+        while let Some(operation) = stream.next_operation() {
+            match operation {
+                Operation::Read(address, buffer) => {
+                    let start = (address - 0x1000) as usize;
+                    buffer.copy_from_slice(&bank[start..start + buffer.len()]);
+                }
+                Operation::Erase(from, to) => {
+                    bank[(from - 0x1000) as usize..(to - 0x1000) as usize].fill(0xff)
+                }
+                Operation::Write(address, data) => {
+                    let start = (address - 0x1000) as usize;
+                    bank[start..start + data.len()].copy_from_slice(data);
+                }
+            }
+            offsets.extend(stream.completed());
+        }
+        offsets
+    }
+
+    fn write(stream: &mut ImageStream<64>, bank: &mut [u8], mut data: &[u8]) -> Vec<Address> {
+        stream.check_write(data).unwrap();
+        let mut offsets = Vec::new();
+        while !data.is_empty() {
+            data = stream.fill(data);
+            offsets.extend(run(stream, bank));
+        }
+        offsets
+    }
+
+    // Resume a download at offset after the first `written` bytes reached the flash
+    fn resume(written: usize, offset: Address) -> (ImageStream<64>, [u8; 0x1000]) {
+        let (image, _) = image();
+        let mut bank = [0_u8; 0x1000];
+        write(
+            &mut stream(Some(HEADER_OFFSET)),
+            &mut bank,
+            &image[..written],
+        );
+
+        let mut stream = stream(Some(HEADER_OFFSET));
+        stream.resume(offset).unwrap();
+        assert!(run(&mut stream, &mut bank).is_empty());
+        (stream, bank)
+    }
+
+    // Write the rest of the image and verify it
+    fn complete(mut stream: ImageStream<64>, mut bank: [u8; 0x1000]) {
+        let (image, header) = image();
+        let written = stream.written as usize;
+        write(&mut stream, &mut bank, &image[written..]);
+        stream.pad();
+        run(&mut stream, &mut bank);
+
+        assert_eq!(stream.verify(), Ok(Some(header)));
+        assert_eq!(bank[..IMAGE_SIZE], image);
+    }
+
+    #[test]
+    fn progress_is_stored_after_every_page() {
+        let (image, header) = image();
+        let mut bank = [0_u8; 0x1000];
+        let mut stream = stream(Some(HEADER_OFFSET));
+        let mut offsets = Vec::new();
+        for chunk in image[..IMAGE_SIZE - 10].chunks(100) {
+            offsets.extend(write(&mut stream, &mut bank, chunk));
+        }
+        offsets.extend(write(&mut stream, &mut bank, &image[IMAGE_SIZE - 10..]));
+
+        assert_eq!(
+            offsets,
+            [0x40, 0x80, 0xc0, 0x100, 0x140, 0x180, 0x1c0, 0x200]
+        );
+        assert_eq!(stream.verify(), Ok(Some(header)));
+        assert_eq!(bank[..IMAGE_SIZE], image);
+        // Sectors behind the image are left alone
+        assert!(bank[IMAGE_SIZE..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn resume_continues_at_the_offset() {
+        let (stream, bank) = resume(0x140, 0x140);
+        assert_eq!(stream.written, 0x140);
+        complete(stream, bank);
+    }
+
+    #[test]
+    fn resume_rewinds_to_the_sector_written_past_the_offset() {
+        let (stream, bank) = resume(0x140, 0x100);
+        assert_eq!(stream.written, 0x100);
+        complete(stream, bank);
+
+        let (stream, bank) = resume(0x140, 0xc0);
+        assert_eq!(stream.written, 0);
+        complete(stream, bank);
+    }
+}
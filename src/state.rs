@@ -1,6 +1,8 @@
-use crate::hardware::Bank;
+use crate::hardware::{Bank, MAX_IMAGES};
+#[cfg(any(feature = "ram-state", feature = "derive"))]
 use crate::log;
 
+#[cfg(any(feature = "ram-state", feature = "derive"))]
 use crc::{Crc, CRC_32_CKSUM};
 #[cfg(feature = "defmt")]
 use defmt::Format;
@@ -15,20 +17,22 @@ use serde::{Deserialize, Serialize};
 #[cfg_attr(feature = "use-defmt", derive(Format))]
 #[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
 #[cfg_attr(feature = "ram-state", derive(Desse, DesseSized))]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Update {
     // No update requested, just jump to the application
     None,
-    // Exchange the current boot image with the one from image specified as index, a signature to
-    // verify against and the size of the firmware image to make the firmware signature
-    // verification succeed
-    Request(Bank),
-    // Revert the current boot image to the from image specified as index
-    Revert(Bank),
+    // Exchange the current boot images with the ones from the update banks of the specified
+    // images
+    Request(ImageSet),
+    // Revert the specified images to the ones from their update banks
+    Revert(ImageSet),
     // An Exchange Operation is in Progress or was interrupted
     Exchanging(ExchangeProgress),
     // An Error during the update has occured!
     Error(UpdateError),
+    // Exchange the specified, already confirmed images back to the previous ones stored in their
+    // update banks
+    Rollback(ImageSet),
 }
 
 #[cfg_attr(feature = "use-defmt", derive(Format))]
@@ -45,6 +49,88 @@ pub enum UpdateError {
     InvalidState,
     /// The Signature provided does not match the PublicKey or Image.
     InvalidSignature,
+    /// An image requires a version of another image which would not be installed after the update
+    DependencyMismatch,
+    /// A rollback was requested, but an update bank does not contain a valid previous image
+    NoPreviousImage,
+}
+
+/// Set of images identified by their index, see [crate::hardware::Config::image_banks]
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "ram-state", derive(Desse, DesseSized))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ImageSet {
+    bits: u8,
+}
+
+impl ImageSet {
+    /// Set containing only the main image
+    pub const MAIN: ImageSet = ImageSet { bits: 1 };
+
+    /// Set containing no image
+    pub const fn empty() -> Self {
+        Self { bits: 0 }
+    }
+
+    /// Set containing the images from index 0 up to, but excluding, `count`
+    pub const fn first(count: u8) -> Self {
+        Self {
+            bits: ((1_u16 << count) - 1) as u8,
+        }
+    }
+
+    /// Set of the images whose bits are set in `bits`, bit 0 being the main image
+    pub const fn from_bits(bits: u8) -> Self {
+        Self { bits }
+    }
+
+    /// Bits of the images in this set, bit 0 being the main image
+    pub const fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    /// Set of the images which are part of both this set and `other`
+    pub const fn intersection(&self, other: ImageSet) -> Self {
+        Self {
+            bits: self.bits & other.bits,
+        }
+    }
+
+    /// Set of the images of this set which are not part of `other`
+    pub const fn difference(&self, other: ImageSet) -> Self {
+        Self {
+            bits: self.bits & !other.bits,
+        }
+    }
+
+    /// Add the image with the given index to this set
+    pub const fn with(self, image: u8) -> Self {
+        Self {
+            bits: self.bits | (1 << image),
+        }
+    }
+
+    /// Whether the image with the given index is part of this set
+    pub const fn contains(&self, image: u8) -> bool {
+        image < 8 && self.bits & (1 << image) != 0
+    }
+
+    /// Whether every image of this set is part of `other` as well
+    pub const fn is_subset(&self, other: ImageSet) -> bool {
+        self.bits & !other.bits == 0
+    }
+
+    /// Whether this set contains no image
+    pub const fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    /// Iterate over the indices of all images in this set in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = u8> {
+        let set = *self;
+        (0..MAX_IMAGES as u8).filter(move |image| set.contains(*image))
+    }
 }
 
 /// Store the progress of the current exchange operation
@@ -57,10 +143,14 @@ pub struct ExchangeProgress {
     pub(crate) a: Bank,
     /// Bank the update is going to
     pub(crate) b: Bank,
-    /// Page the operation has last copied
+    /// Number of pages already exchanged, i.e. the page the operation continues at
     pub(crate) page_index: u32,
     /// Whether this exchange resulted from a Request (false) or a Revert (true)
     pub(crate) recovering: bool,
+    /// Index of the image currently being exchanged
+    pub(crate) image: u8,
+    /// All images exchanged by this operation, in ascending order
+    pub(crate) images: ImageSet,
 }
 
 /// Struct used to store the state of the bootloader situation in NVM
@@ -75,6 +165,44 @@ pub struct MoonbootState {
     /// the bootloader, the bootloader starts with this variable set to Revert and thus exchanges
     /// the two images again, doing a downgrade because of a failed boot
     pub update: Update,
+    /// Images of a Revert state which have already been marked as successfully booted. Only once
+    /// every updated image is confirmed, the update state is set to None. Otherwise all of them
+    /// are reverted together.
+    pub confirmed: ImageSet,
+    /// Images the bootloader reverted to their previous version during the last boot
+    pub reverted: ImageSet,
+    /// Progress of the download into an update bank, to continue it after a reset
+    pub download: DownloadCursor,
+    /// Number of boots the bootloader left the update state unchanged since it last changed it,
+    /// e.g. to allow several boot attempts before reverting an update
+    pub boots: u32,
+}
+
+impl Default for MoonbootState {
+    fn default() -> Self {
+        Self {
+            update: Update::None,
+            confirmed: ImageSet::empty(),
+            reverted: ImageSet::empty(),
+            download: DownloadCursor::default(),
+            boots: 0,
+        }
+    }
+}
+
+/// Progress of writing an image into an update bank, see
+/// `MoonbootManager::resume_update_writer`
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[cfg_attr(feature = "derive", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "ram-state", derive(Desse, DesseSized))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DownloadCursor {
+    /// Index of the image being written
+    pub image: u8,
+    /// Identifier of the image being written, chosen by the application
+    pub id: u32,
+    /// Number of bytes already written to the update bank, zero if there is nothing to resume
+    pub offset: u32,
 }
 
 /// Hardware abstraction for the state storage. Can for example be stored on a flash bank, or in
@@ -87,75 +215,653 @@ pub trait State {
     fn write(&mut self, data: MoonbootState) -> Result<(), ()>;
 }
 
-/// Size of the serialized state
-pub const STATE_SERIALIZED_MAX_SIZE: usize = MoonbootState::SIZE;
+/// Version of the serialized state format understood by this version of moonboot. Newer versions
+/// may only append fields to [MoonbootState] or variants to its enums, so the serialized state of
+/// an older version is always a valid prefix of the current one.
+pub const STATE_FORMAT_VERSION: u8 = 2;
+/// Size of the [StateHeader] preceding the serialized state
+pub const STATE_HEADER_SIZE: usize = 4;
+/// Size of the serialized state including its header. This leaves headroom for fields added by
+/// future versions, so the size of the state storage does not change between versions.
+pub const STATE_SERIALIZED_MAX_SIZE: usize = 128;
+
+/// Header preceding the serialized state, allowing bootloader and application to exchange the
+/// state even if they are built with different versions of moonboot
+#[cfg_attr(feature = "use-defmt", derive(Format))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateHeader {
+    /// Format version of the state following this header
+    pub version: u8,
+    /// Oldest format version understood by everyone who has written this state. A state which
+    /// requires a newer format is not written, as the other side would not be able to read it.
+    pub compatible_version: u8,
+    /// Length of the serialized state following this header
+    pub length: u16,
+}
+
+impl StateHeader {
+    /// Serialize the header
+    pub fn to_bytes(&self) -> [u8; STATE_HEADER_SIZE] {
+        let length = self.length.to_le_bytes();
+        [self.version, self.compatible_version, length[0], length[1]]
+    }
+
+    /// Deserialize the header
+    pub fn from_bytes(bytes: &[u8; STATE_HEADER_SIZE]) -> Self {
+        Self {
+            version: bytes[0],
+            compatible_version: bytes[1],
+            length: u16::from_le_bytes([bytes[2], bytes[3]]),
+        }
+    }
+}
+
+impl MoonbootState {
+    /// Oldest format version able to represent this state. The state is always written in this
+    /// version, so it can be read by older versions of moonboot as long as it does not make use of
+    /// newer features.
+    pub fn required_version(&self) -> u8 {
+        match self.update {
+            Update::None | Update::Request(_) | Update::Revert(_) | Update::Exchanging(_) => 1,
+            // Version 2 added rollbacks
+            Update::Rollback(_) | Update::Error(UpdateError::NoPreviousImage) => 2,
+            Update::Error(_) => 1,
+        }
+    }
+
+    /// Migrate a state read in an older format `version` to the current one. Fields appended by
+    /// newer versions are already zeroed, this only has to fix up their meaning.
+    #[cfg(any(feature = "ram-state", feature = "derive"))]
+    fn migrate(self, version: u8) -> Self {
+        if version < STATE_FORMAT_VERSION {
+            log::info!(
+                "Migrating state from format version {} to {}",
+                version,
+                STATE_FORMAT_VERSION
+            );
+        }
+        // Version 1 is the first versioned format, add migrations of older versions here
+        self
+    }
+}
+
+/// Serialize the state including its [StateHeader] in the oldest format able to represent it.
+/// Fails if this format is newer than `compatible_version`, which should be taken from the header
+/// of the state currently stored, see [StateHeader::compatible_version].
+#[cfg(feature = "ram-state")]
+pub fn serialize_state(
+    state: &MoonbootState,
+    compatible_version: u8,
+) -> Result<[u8; STATE_SERIALIZED_MAX_SIZE], ()> {
+    let version = state.required_version();
+    if version > compatible_version {
+        log::error!(
+            "State requires format version {}, but only version {} is supported by the other side",
+            version,
+            compatible_version
+        );
+        return Err(());
+    }
+
+    let header = StateHeader {
+        version,
+        compatible_version: core::cmp::min(compatible_version, STATE_FORMAT_VERSION),
+        length: MoonbootState::SIZE as u16,
+    };
+
+    let mut bytes = [0_u8; STATE_SERIALIZED_MAX_SIZE];
+    bytes[..STATE_HEADER_SIZE].copy_from_slice(&header.to_bytes());
+    bytes[STATE_HEADER_SIZE..STATE_HEADER_SIZE + MoonbootState::SIZE]
+        .copy_from_slice(&Desse::serialize(state));
+    Ok(bytes)
+}
+
+/// Deserialize a state written by [serialize_state], possibly by another version of moonboot.
+/// Fields unknown to this version are ignored, fields missing in an older version are migrated.
+/// Returns None if the state was written in a format newer than [STATE_FORMAT_VERSION].
+#[cfg(feature = "ram-state")]
+pub fn deserialize_state(
+    bytes: &[u8; STATE_SERIALIZED_MAX_SIZE],
+) -> Option<(StateHeader, MoonbootState)> {
+    let mut header = [0_u8; STATE_HEADER_SIZE];
+    header.copy_from_slice(&bytes[..STATE_HEADER_SIZE]);
+    let header = StateHeader::from_bytes(&header);
+
+    let length = header.length as usize;
+    if header.version == 0
+        || header.version > STATE_FORMAT_VERSION
+        || length > STATE_SERIALIZED_MAX_SIZE - STATE_HEADER_SIZE
+    {
+        log::warn!(
+            "Unsupported state format version {} with length {}",
+            header.version,
+            length
+        );
+        return None;
+    }
+
+    let known = core::cmp::min(length, MoonbootState::SIZE);
+    let mut data = [0_u8; MoonbootState::SIZE];
+    data[..known].copy_from_slice(&bytes[STATE_HEADER_SIZE..STATE_HEADER_SIZE + known]);
+
+    let state = MoonbootState::deserialize_from(&data).migrate(header.version);
+    Some((header, state))
+}
+
+#[cfg(feature = "ram-state")]
+const _: () = assert!(
+    STATE_HEADER_SIZE + MoonbootState::SIZE <= STATE_SERIALIZED_MAX_SIZE,
+    "The state does not fit into STATE_SERIALIZED_MAX_SIZE"
+);
 /// Type used to store the shared state CRC
 pub type StateCrcType = u32;
+#[cfg(any(feature = "ram-state", feature = "derive"))]
 const CRC: Crc<StateCrcType> = Crc::<StateCrcType>::new(&CRC_32_CKSUM);
 
+#[cfg(any(feature = "ram-state", feature = "derive"))]
 fn checksum(bytes: &[u8]) -> StateCrcType {
     CRC.checksum(bytes)
 }
 
+/// Size of the RAM reserved for the state when it is stored in RAM. The reserved size does not
+/// depend on the size of the state, so the state is found at the same address by bootloader and
+/// application even if they are built with different versions of moonboot.
+pub const STATE_RESERVED_SIZE: usize = 256;
+
+/// RAM reserved for the state in the `.moonboot_state` section, shared by the RAM based state
+/// implementations
+#[cfg(any(feature = "ram-state", feature = "derive"))]
+pub(crate) mod section {
+    use super::STATE_RESERVED_SIZE;
+    use core::{mem::MaybeUninit, ptr::addr_of_mut};
+
+    #[repr(C, align(4))]
+    struct Section([u8; STATE_RESERVED_SIZE]);
+
+    #[link_section = ".moonboot_state"]
+    static mut SECTION: MaybeUninit<Section> = MaybeUninit::uninit();
+
+    pub(crate) fn section() -> *mut u8 {
+        addr_of_mut!(SECTION) as *mut u8
+    }
+
+    /// Location and length of the RAM reserved for the state
+    #[cfg(feature = "cortex-m")]
+    pub(crate) fn region() -> (crate::Address, usize) {
+        (section() as crate::Address, STATE_RESERVED_SIZE)
+    }
+}
+
 /// State stored in the RAM
 /// TODO: Move to hardware folder together with state trait?
 #[cfg(feature = "ram-state")]
 pub mod ram {
     use super::*;
+    use core::ptr::{addr_of, addr_of_mut};
+
+    /// Marker at the start of the RAM state, the lowest byte holds the layout version
+    const STATE_MAGIC: u32 = 0x4d42_5301;
 
     /// State read and written to RAM. This assumes the device is never powered off / the ram is never
-    /// reset!
+    /// reset! The state is placed in the `.moonboot_state` section, which the linker scripts
+    /// generated by moonboot-codegen put at the end of the RAM without initializing it.
     pub struct RamState;
 
-    extern "C" {
-        static mut _moonboot_state_crc_start: StateCrcType;
-        static mut _moonboot_state_data_start: [u8; STATE_SERIALIZED_MAX_SIZE];
-        // TODO: Move these as normal variables to linker sections via #[link] macro?
+    #[repr(C)]
+    struct RamStateStorage {
+        magic: u32,
+        len: u32,
+        crc: StateCrcType,
+        data: [u8; STATE_SERIALIZED_MAX_SIZE],
+    }
+
+    const _: () = assert!(
+        core::mem::size_of::<RamStateStorage>() <= STATE_RESERVED_SIZE,
+        "The state does not fit into the reserved RAM"
+    );
+
+    fn storage() -> *mut RamStateStorage {
+        section::section() as *mut RamStateStorage
+    }
+
+    impl RamState {
+        /// Header of the currently stored state, if there is a valid one
+        fn stored_header(&self) -> Option<StateHeader> {
+            let storage = storage();
+            let (magic, len, crc, data) = unsafe {
+                (
+                    addr_of!((*storage).magic).read_volatile(),
+                    addr_of!((*storage).len).read_volatile(),
+                    addr_of!((*storage).crc).read_volatile(),
+                    addr_of!((*storage).data).read_volatile(),
+                )
+            };
+
+            if magic != STATE_MAGIC
+                || len as usize != STATE_SERIALIZED_MAX_SIZE
+                || crc != checksum(&data)
+            {
+                return None;
+            }
+
+            let mut header = [0_u8; STATE_HEADER_SIZE];
+            header.copy_from_slice(&data[..STATE_HEADER_SIZE]);
+            Some(StateHeader::from_bytes(&header))
+        }
     }
 
     impl State for RamState {
         fn read(&mut self) -> MoonbootState {
-            let crc = unsafe { _moonboot_state_crc_start };
+            let storage = storage();
+            let (magic, len, crc, data) = unsafe {
+                (
+                    addr_of!((*storage).magic).read_volatile(),
+                    addr_of!((*storage).len).read_volatile(),
+                    addr_of!((*storage).crc).read_volatile(),
+                    addr_of!((*storage).data).read_volatile(),
+                )
+            };
 
-            log::info!(
-                "Reading data with len: {}, CRC: {}",
-                STATE_SERIALIZED_MAX_SIZE,
-                crc
-            );
+            log::info!("Reading data with len: {}, CRC: {}", len, crc);
 
-            let checksum = checksum(unsafe { &_moonboot_state_data_start });
-            if crc == checksum {
-                let data =
-                    MoonbootState::deserialize_from(unsafe { &_moonboot_state_data_start });
-                log::trace!("CRC Match! {}: {:?}", crc, data);
-                return data;
+            if magic != STATE_MAGIC || len as usize != STATE_SERIALIZED_MAX_SIZE {
+                log::warn!(
+                    "No compatible state found (magic: {:x}, len: {}), expected len: {}",
+                    magic,
+                    len,
+                    STATE_SERIALIZED_MAX_SIZE
+                );
             } else {
-                log::trace!("CRC Mismatch! {} vs {}", crc, checksum);
+                let checksum = checksum(&data);
+                if crc == checksum {
+                    if let Some((header, data)) = deserialize_state(&data) {
+                        log::trace!("CRC Match! {}: {:?} ({:?})", crc, data, header);
+                        return data;
+                    }
+                } else {
+                    log::trace!("CRC Mismatch! {} vs {}", crc, checksum);
+                }
             }
 
-            MoonbootState {
-                update: Update::None,
-            }
+            MoonbootState::default()
         }
 
         fn write(&mut self, data: MoonbootState) -> Result<(), ()> {
             log::trace!("Writing data {:?}", data);
 
-            unsafe { _moonboot_state_data_start = data.serialize() };
-            log::trace!("Written data: {:?}", unsafe {
-                &_moonboot_state_data_start
-            });
+            // Only write formats the other side is able to read
+            let compatible_version = self
+                .stored_header()
+                .map_or(STATE_FORMAT_VERSION, |header| header.compatible_version);
+            let data = serialize_state(&data, compatible_version)?;
+            log::trace!("Written data: {:?}", data);
 
+            let crc = checksum(&data);
+            let storage = storage();
             unsafe {
-                _moonboot_state_crc_start = checksum(&_moonboot_state_data_start);
+                addr_of_mut!((*storage).magic).write_volatile(STATE_MAGIC);
+                addr_of_mut!((*storage).len).write_volatile(STATE_SERIALIZED_MAX_SIZE as u32);
+                addr_of_mut!((*storage).data).write_volatile(data);
+                addr_of_mut!((*storage).crc).write_volatile(crc);
             }
             log::info!(
                 "Written len: {}, checksum: {}",
                 STATE_SERIALIZED_MAX_SIZE,
-                unsafe { _moonboot_state_crc_start }
+                crc
+            );
+
+            Ok(())
+        }
+    }
+}
+
+/// State serialized with postcard and stored in RAM, for applications already using serde and
+/// postcard. The serialized state is followed by a CRC and framed with COBS, so host tools can
+/// decode it from a memory dump with [decode].
+#[cfg(feature = "derive")]
+pub mod postcard {
+    use super::*;
+
+    /// Maximum size of a state framed by [encode], including the terminating zero
+    pub const FRAME_MAX_SIZE: usize =
+        STATE_SERIALIZED_MAX_SIZE + STATE_SERIALIZED_MAX_SIZE / 254 + 2;
+
+    const CRC_SIZE: usize = core::mem::size_of::<StateCrcType>();
+
+    const _: () = assert!(
+        FRAME_MAX_SIZE <= STATE_RESERVED_SIZE,
+        "The framed state does not fit into the reserved RAM"
+    );
+
+    /// Frame the state with its [StateHeader] and CRC into `frame`, returning the length of the
+    /// frame. Fails if `frame` is too small, or the state requires a format newer than
+    /// `compatible_version`, see [StateHeader::compatible_version].
+    pub fn encode(
+        state: &MoonbootState,
+        compatible_version: u8,
+        frame: &mut [u8],
+    ) -> Result<usize, ()> {
+        let version = state.required_version();
+        if version > compatible_version {
+            log::error!(
+                "State requires format version {}, but only version {} is supported by the other side",
+                version,
+                compatible_version
             );
+            return Err(());
+        }
+
+        let mut raw = [0_u8; STATE_SERIALIZED_MAX_SIZE];
+        let length = ::postcard::to_slice(
+            state,
+            &mut raw[STATE_HEADER_SIZE..STATE_SERIALIZED_MAX_SIZE - CRC_SIZE],
+        )
+        .map_err(|_| ())?
+        .len();
+
+        let header = StateHeader {
+            version,
+            compatible_version: core::cmp::min(compatible_version, STATE_FORMAT_VERSION),
+            length: length as u16,
+        };
+        raw[..STATE_HEADER_SIZE].copy_from_slice(&header.to_bytes());
+
+        let end = STATE_HEADER_SIZE + length;
+        let crc = checksum(&raw[..end]);
+        raw[end..end + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+
+        let encoded = cobs::try_encode(&raw[..end + CRC_SIZE], frame)?;
+        *frame.get_mut(encoded).ok_or(())? = 0;
+        Ok(encoded + 1)
+    }
+
+    /// Decode a state framed by [encode], possibly by another version of moonboot. Only the bytes
+    /// up to the first zero are considered. Returns None if the frame is corrupted or was written
+    /// in a format newer than [STATE_FORMAT_VERSION].
+    pub fn decode(frame: &[u8]) -> Option<(StateHeader, MoonbootState)> {
+        let end = frame.iter().position(|byte| *byte == 0)?;
+        let mut raw = [0_u8; FRAME_MAX_SIZE];
+        let length = cobs::decode(&frame[..end], &mut raw).ok()?;
+        if length < STATE_HEADER_SIZE + CRC_SIZE {
+            return None;
+        }
+
+        let (data, crc) = raw[..length].split_at(length - CRC_SIZE);
+        let mut crc_bytes = [0_u8; CRC_SIZE];
+        crc_bytes.copy_from_slice(crc);
+        if checksum(data) != StateCrcType::from_le_bytes(crc_bytes) {
+            log::trace!("CRC Mismatch!");
+            return None;
+        }
+
+        let mut header = [0_u8; STATE_HEADER_SIZE];
+        header.copy_from_slice(&data[..STATE_HEADER_SIZE]);
+        let header = StateHeader::from_bytes(&header);
+        if header.version == 0
+            || header.version > STATE_FORMAT_VERSION
+            || header.length as usize != data.len() - STATE_HEADER_SIZE
+            || header.length as usize > STATE_SERIALIZED_MAX_SIZE - STATE_HEADER_SIZE
+        {
+            log::warn!(
+                "Unsupported state format version {} with length {}",
+                header.version,
+                header.length
+            );
+            return None;
+        }
+
+        // Fields appended by newer versions are ignored by postcard. Postcard is not
+        // self-describing, so fields missing in an older version are zeroed like by
+        // deserialize_state, which postcard decodes as zero, false, None or the first variant.
+        let mut padded = [0_u8; STATE_SERIALIZED_MAX_SIZE];
+        let length = data.len() - STATE_HEADER_SIZE;
+        padded[..length].copy_from_slice(&data[STATE_HEADER_SIZE..]);
+        let state: MoonbootState = ::postcard::from_bytes(&padded).ok()?;
+        Some((header, state.migrate(header.version)))
+    }
+
+    /// State serialized with postcard, read and written to RAM. This assumes the device is never
+    /// powered off / the ram is never reset! Like [super::ram::RamState], the state is placed in
+    /// the `.moonboot_state` section.
+    pub struct PostcardState;
+
+    fn storage() -> *mut [u8; STATE_RESERVED_SIZE] {
+        section::section() as *mut [u8; STATE_RESERVED_SIZE]
+    }
+
+    impl State for PostcardState {
+        fn read(&mut self) -> MoonbootState {
+            let frame = unsafe { storage().read_volatile() };
+
+            match decode(&frame) {
+                Some((header, data)) => {
+                    log::trace!("Read data: {:?} ({:?})", data, header);
+                    data
+                }
+                None => {
+                    log::warn!("No compatible state found");
+                    MoonbootState::default()
+                }
+            }
+        }
+
+        fn write(&mut self, data: MoonbootState) -> Result<(), ()> {
+            log::trace!("Writing data {:?}", data);
+
+            // Only write formats the other side is able to read
+            let compatible_version = decode(&unsafe { storage().read_volatile() })
+                .map_or(STATE_FORMAT_VERSION, |(header, _)| {
+                    header.compatible_version
+                });
+
+            let mut frame = [0_u8; STATE_RESERVED_SIZE];
+            let length = encode(&data, compatible_version, &mut frame)?;
+            unsafe { storage().write_volatile(frame) };
+            log::info!("Written frame with len: {}", length);
 
             Ok(())
         }
     }
 }
+
+#[cfg(all(test, any(feature = "ram-state", feature = "derive")))]
+mod tests {
+    use super::*;
+
+    fn example_state() -> MoonbootState {
+        MoonbootState {
+            update: Update::Revert(ImageSet::empty().with(0).with(2)),
+            confirmed: ImageSet::empty().with(2),
+            reverted: ImageSet::empty().with(1),
+            download: DownloadCursor {
+                image: 1,
+                id: 0x1234_5678,
+                offset: 4096,
+            },
+            boots: 3,
+        }
+    }
+
+    fn assert_state_eq(a: &MoonbootState, b: &MoonbootState) {
+        assert_eq!(a.update, b.update);
+        assert_eq!(a.confirmed, b.confirmed);
+        assert_eq!(a.reverted, b.reverted);
+        assert_eq!(a.download, b.download);
+        assert_eq!(a.boots, b.boots);
+    }
+
+    #[cfg(feature = "ram-state")]
+    fn with_header(
+        mut bytes: [u8; STATE_SERIALIZED_MAX_SIZE],
+        version: u8,
+        length: usize,
+    ) -> [u8; STATE_SERIALIZED_MAX_SIZE] {
+        let header = StateHeader {
+            version,
+            compatible_version: version,
+            length: length as u16,
+        };
+        bytes[..STATE_HEADER_SIZE].copy_from_slice(&header.to_bytes());
+        bytes
+    }
+
+    #[cfg(feature = "ram-state")]
+    #[test]
+    fn state_round_trip() {
+        let state = example_state();
+        let bytes = serialize_state(&state, STATE_FORMAT_VERSION).unwrap();
+        let (header, read) = deserialize_state(&bytes).unwrap();
+
+        assert_eq!(header.version, 1);
+        assert_eq!(header.compatible_version, STATE_FORMAT_VERSION);
+        assert_eq!(header.length as usize, MoonbootState::SIZE);
+        assert_state_eq(&state, &read);
+    }
+
+    #[cfg(feature = "ram-state")]
+    #[test]
+    fn state_requiring_newer_version_is_not_written() {
+        let state = MoonbootState {
+            update: Update::Rollback(ImageSet::empty().with(0)),
+            ..Default::default()
+        };
+        assert!(serialize_state(&state, 1).is_err());
+
+        let bytes = serialize_state(&state, 2).unwrap();
+        let (header, read) = deserialize_state(&bytes).unwrap();
+        assert_eq!(header.version, 2);
+        assert_state_eq(&state, &read);
+    }
+
+    #[cfg(feature = "ram-state")]
+    #[test]
+    fn state_of_older_version_is_zero_extended() {
+        let state = example_state();
+        let length = MoonbootState::SIZE - core::mem::size_of::<u32>();
+        let mut bytes = serialize_state(&state, STATE_FORMAT_VERSION).unwrap();
+        // An older version did not know the boot counter appended last
+        bytes[STATE_HEADER_SIZE + length..].fill(0xa5);
+        let bytes = with_header(bytes, 1, length);
+
+        let (_, read) = deserialize_state(&bytes).unwrap();
+        assert_state_eq(
+            &MoonbootState {
+                boots: 0,
+                ..example_state()
+            },
+            &read,
+        );
+    }
+
+    #[cfg(feature = "ram-state")]
+    #[test]
+    fn fields_of_newer_version_are_ignored() {
+        let state = example_state();
+        let mut bytes = serialize_state(&state, STATE_FORMAT_VERSION).unwrap();
+        bytes[STATE_HEADER_SIZE + MoonbootState::SIZE..].fill(0xa5);
+        let bytes = with_header(bytes, STATE_FORMAT_VERSION, MoonbootState::SIZE + 8);
+
+        let (_, read) = deserialize_state(&bytes).unwrap();
+        assert_state_eq(&state, &read);
+    }
+
+    #[cfg(feature = "ram-state")]
+    #[test]
+    fn state_of_unknown_version_is_rejected() {
+        let bytes = serialize_state(&example_state(), STATE_FORMAT_VERSION).unwrap();
+        assert!(deserialize_state(&with_header(bytes, 0, MoonbootState::SIZE)).is_none());
+        assert!(deserialize_state(&with_header(
+            bytes,
+            STATE_FORMAT_VERSION + 1,
+            MoonbootState::SIZE
+        ))
+        .is_none());
+        assert!(deserialize_state(&with_header(bytes, 1, STATE_SERIALIZED_MAX_SIZE)).is_none());
+    }
+
+    // Frame a serialized state like postcard::encode does
+    #[cfg(feature = "derive")]
+    fn postcard_frame(version: u8, payload: &[u8]) -> [u8; postcard::FRAME_MAX_SIZE] {
+        let header = StateHeader {
+            version,
+            compatible_version: version,
+            length: payload.len() as u16,
+        };
+        let mut raw = [0_u8; STATE_SERIALIZED_MAX_SIZE];
+        let end = STATE_HEADER_SIZE + payload.len();
+        raw[..STATE_HEADER_SIZE].copy_from_slice(&header.to_bytes());
+        raw[STATE_HEADER_SIZE..end].copy_from_slice(payload);
+        let crc = checksum(&raw[..end]).to_le_bytes();
+        raw[end..end + crc.len()].copy_from_slice(&crc);
+
+        let mut frame = [0_u8; postcard::FRAME_MAX_SIZE];
+        cobs::encode(&raw[..end + crc.len()], &mut frame);
+        frame
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn postcard_round_trip() {
+        let state = example_state();
+        let mut frame = [0_u8; postcard::FRAME_MAX_SIZE];
+        let length = postcard::encode(&state, STATE_FORMAT_VERSION, &mut frame).unwrap();
+        assert_eq!(frame[length - 1], 0);
+
+        let (header, read) = postcard::decode(&frame[..length]).unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!(header.compatible_version, STATE_FORMAT_VERSION);
+        assert_state_eq(&state, &read);
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn postcard_frame_of_older_version_is_zero_extended() {
+        let state = example_state();
+        // An older version did not know the boot counter appended last
+        let mut payload = [0_u8; STATE_SERIALIZED_MAX_SIZE];
+        let payload = ::postcard::to_slice(
+            &(
+                state.update,
+                state.confirmed,
+                state.reverted,
+                state.download,
+            ),
+            &mut payload,
+        )
+        .unwrap();
+
+        let (header, read) = postcard::decode(&postcard_frame(1, payload)).unwrap();
+        assert_eq!(header.length as usize, payload.len());
+        assert_state_eq(
+            &MoonbootState {
+                boots: 0,
+                ..example_state()
+            },
+            &read,
+        );
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn postcard_fields_of_newer_version_are_ignored() {
+        let state = example_state();
+        let mut payload = [0_u8; STATE_SERIALIZED_MAX_SIZE];
+        let payload = ::postcard::to_slice(&(&state, 0x42_u8, 7_u32), &mut payload).unwrap();
+
+        let (_, read) = postcard::decode(&postcard_frame(STATE_FORMAT_VERSION, payload)).unwrap();
+        assert_state_eq(&state, &read);
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn corrupted_postcard_frame_is_rejected() {
+        let mut frame = [0_u8; postcard::FRAME_MAX_SIZE];
+        let length = postcard::encode(&example_state(), STATE_FORMAT_VERSION, &mut frame).unwrap();
+        frame[length / 2] ^= 0x10;
+        assert!(postcard::decode(&frame[..length]).is_none());
+
+        let mut payload = [0_u8; STATE_SERIALIZED_MAX_SIZE];
+        let payload = ::postcard::to_slice(&example_state(), &mut payload).unwrap();
+        assert!(postcard::decode(&postcard_frame(STATE_FORMAT_VERSION + 1, payload)).is_none());
+    }
+}